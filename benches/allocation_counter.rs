@@ -0,0 +1,89 @@
+//! Compare the overhead of `CountingAllocator` with plain `System` under multithreaded allocation.
+//!
+//! ```text
+//! cargo bench --bench allocation_counter [threads]
+//! ```
+//!
+//! Three runs are compared: `System`, `CountingAllocator` wrapping `System`, and the same with
+//! per-thread counters enabled. Every run allocates and frees on `threads` threads at once,
+//! which is the number of CPUs by default.
+//!
+//! The counters are sharded to avoid contention between threads, which doesn't show up
+//! with fewer than 2 CPUs, so run it on a multicore machine with at least 2 threads.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::Barrier;
+use std::time::{Duration, Instant};
+
+use perf_monitor::mem::CountingAllocator;
+
+static COUNTING: CountingAllocator = CountingAllocator::new(System);
+
+const ROUNDS: usize = 1_000_000;
+/// the number of live allocations per thread, freed in FIFO order.
+const LIVE: usize = 64;
+
+/// Allocate and free `ROUNDS` blocks of mixed sizes on every thread concurrently,
+/// return the wall time divided by `ROUNDS`.
+fn run(allocator: &'static (dyn GlobalAlloc + Sync), threads: usize) -> Duration {
+    let barrier = Barrier::new(threads + 1);
+    std::thread::scope(|scope| {
+        let handles = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut live = [(std::ptr::null_mut(), Layout::new::<u8>()); LIVE];
+                    barrier.wait();
+                    for round in 0..ROUNDS {
+                        let layout = Layout::from_size_align(16 << (round % 7), 8).unwrap();
+                        let (ptr, old) = std::mem::replace(
+                            &mut live[round % LIVE],
+                            (unsafe { allocator.alloc(layout) }, layout),
+                        );
+                        if !ptr.is_null() {
+                            unsafe { allocator.dealloc(ptr, old) };
+                        }
+                    }
+                    for (ptr, layout) in live {
+                        unsafe { allocator.dealloc(ptr, layout) };
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        barrier.wait();
+        let start = Instant::now();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        start.elapsed() / ROUNDS as u32
+    })
+}
+
+fn main() {
+    let threads = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()));
+    println!(
+        "{} threads, {} rounds of alloc + dealloc per thread",
+        threads, ROUNDS
+    );
+
+    let system = run(&System, threads);
+    println!("System: {:?}/round", system);
+
+    CountingAllocator::enable();
+    let counting = run(&COUNTING, threads);
+    println!(
+        "CountingAllocator: {:?}/round, overhead {:?}",
+        counting,
+        counting.saturating_sub(system)
+    );
+
+    CountingAllocator::enable_thread_counters();
+    let thread_counters = run(&COUNTING, threads);
+    println!(
+        "CountingAllocator with thread counters: {:?}/round, overhead {:?}",
+        thread_counters,
+        thread_counters.saturating_sub(system)
+    );
+}
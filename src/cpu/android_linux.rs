@@ -93,3 +93,70 @@ pub fn cpu_time() -> Result<Duration> {
     }
     Ok(timespec_to_duration(unsafe { timespec.assume_init() }))
 }
+
+/// Get the address range of the mapping containing `addr` from `/proc/self/maps`.
+fn find_mapping(addr: usize) -> Result<(usize, usize)> {
+    let maps = std::fs::read_to_string("/proc/self/maps")?;
+    maps.lines()
+        .filter_map(|line| {
+            let (start, end) = line.split(' ').next()?.split_once('-')?;
+            Some((
+                usize::from_str_radix(start, 16).ok()?,
+                usize::from_str_radix(end, 16).ok()?,
+            ))
+        })
+        .find(|&(start, end)| start <= addr && addr < end)
+        .ok_or_else(|| Error::new(std::io::ErrorKind::NotFound, "stack mapping is not found"))
+}
+
+pub fn thread_stack(ThreadId(thread): ThreadId) -> Result<super::ThreadStack> {
+    let mut attr = MaybeUninit::<libc::pthread_attr_t>::uninit();
+    let ret = unsafe { libc::pthread_getattr_np(thread, attr.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(Error::from_raw_os_error(ret));
+    }
+    let mut attr = unsafe { attr.assume_init() };
+    let mut stack_addr = std::ptr::null_mut();
+    let mut stack_size = 0;
+    let mut guard_size = 0;
+    let ret = unsafe {
+        let ret = libc::pthread_attr_getstack(&attr, &mut stack_addr, &mut stack_size);
+        if ret == 0 {
+            libc::pthread_attr_getguardsize(&attr, &mut guard_size)
+        } else {
+            ret
+        }
+    };
+    unsafe { libc::pthread_attr_destroy(&mut attr) };
+    if ret != 0 {
+        return Err(Error::from_raw_os_error(ret));
+    }
+
+    // the stack grows down from `top`, the guard pages are below `stack_addr`.
+    let top = stack_addr as usize + stack_size;
+    // the stack of the main thread is mapped on demand, so only the mapped part can be scanned.
+    let (mapping_start, _) = find_mapping(top - 1)?;
+    let bottom = (stack_addr as usize).max(mapping_start);
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let mut resident = vec![0u8; (top - bottom).div_ceil(page_size)];
+    let ret = unsafe {
+        libc::mincore(
+            bottom as *mut libc::c_void,
+            top - bottom,
+            resident.as_mut_ptr() as *mut _,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    let peak_usage = resident
+        .iter()
+        .position(|page| page & 1 != 0)
+        .map_or(0, |lowest| top - (bottom + lowest * page_size));
+    Ok(super::ThreadStack {
+        size: stack_size,
+        guard_size,
+        peak_usage,
+    })
+}
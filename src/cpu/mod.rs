@@ -7,6 +7,8 @@
 //! the cpu usage will beyond 100%, for example returning 2.8 means 280% cpu usage.
 //! If normalized value is what you expected, divide the returning by processor_numbers.
 //!
+//! On Linux and Android, `ThreadStack` reports the stack size of a thread and its high-water mark.
+//!
 //! ## Example
 //!
 //! ```
@@ -119,6 +121,40 @@ impl ThreadStat {
     }
 }
 
+/// Stack size and an estimate of the peak stack use of a thread.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[cfg_attr(doc, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadStack {
+    /// bytes of the stack, excluding the guard pages.
+    pub size: usize,
+    /// bytes of the guard pages below the stack.
+    pub guard_size: usize,
+    /// bytes from the top of the stack to the lowest page which is resident, i.e. the high-water mark.
+    ///
+    /// It's estimated by `mincore`, so it may be lower if touched pages are swapped out,
+    /// or higher if the stack is reused from an exited thread.
+    pub peak_usage: usize,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl ThreadStack {
+    /// return the stack of current thread.
+    pub fn cur() -> Result<Self> {
+        Self::build(ThreadId::current())
+    }
+
+    /// return the stack of specified thread, which must be alive.
+    pub fn build(thread_id: ThreadId) -> Result<Self> {
+        platform::thread_stack(thread_id)
+    }
+
+    /// return the ratio of the peak use to the size, a thread is about to overflow when it's close to 1.
+    pub fn peak_ratio(&self) -> f64 {
+        self.peak_usage as f64 / self.size as f64
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -170,4 +206,37 @@ mod test {
         let usage = stat.cpu().unwrap();
         assert!(usage > 0.5)
     }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_thread_stack() {
+        fn touch(depth: usize) -> u8 {
+            let mut buf = [0u8; 1024];
+            std::hint::black_box(&mut buf);
+            if depth == 0 {
+                buf[0]
+            } else {
+                touch(depth - 1).wrapping_add(buf[1])
+            }
+        }
+
+        let stack = std::thread::Builder::new()
+            .stack_size(1 << 20)
+            .spawn(|| {
+                let before = ThreadStack::cur().unwrap();
+                std::hint::black_box(touch(256));
+                let after = ThreadStack::cur().unwrap();
+                assert!(after.peak_usage >= before.peak_usage + 256 * 1024);
+                after
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(stack.size >= 1 << 20);
+        assert!(stack.peak_usage <= stack.size);
+        assert!(stack.peak_ratio() > 0.25);
+
+        let main = ThreadStack::cur().unwrap();
+        assert!(main.peak_usage > 0 && main.peak_usage <= main.size);
+    }
 }
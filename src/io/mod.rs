@@ -1,6 +1,31 @@
 //! Get io usage for current process.
+//!
+//! `IOStat` reports the io rates since the last call, the same way as `cpu::ProcessStat`.
+//! `file_cache_residency` reports how much of a file is in the page cache.
 use thiserror::Error;
 
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "windows"
+))]
+mod stat;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "windows"
+))]
+pub use stat::{IORate, IOStat};
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+mod page_cache;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use page_cache::open_files_cache_residency;
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+pub use page_cache::{file_cache_residency, file_cache_residency_fd, CacheResidency};
+
 #[derive(Error, Debug)]
 #[error("IOStatsError({code}):{msg}")]
 pub struct IOStatsError {
@@ -39,7 +64,20 @@ pub struct IOStats {
 
     /// the number of bytes written (cumulative)
     pub write_bytes: u64,
+
+    /// (linux) the number of bytes read by syscalls like `read` and `pread` (cumulative),
+    /// including reads satisfied by the page cache, whereas `read_bytes` are fetched from the storage.
+    pub logical_read_bytes: u64,
+
+    /// (linux) the number of bytes written by syscalls like `write` and `pwrite` (cumulative),
+    /// including writes to the page cache which are not written back yet.
+    pub logical_write_bytes: u64,
+
+    /// (linux) the number of bytes which were counted by `write_bytes` but are never written back (cumulative),
+    /// e.g. a dirty file truncated or deleted before written back.
+    pub cancelled_write_bytes: u64,
 }
+
 /// Get the io stats of current process. Most platforms are supported.
 #[cfg(any(
     target_os = "linux",
@@ -53,22 +91,25 @@ pub fn get_process_io_stats() -> Result<IOStats, IOStatsError> {
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
 fn get_process_io_stats_impl() -> Result<IOStats, IOStatsError> {
-    use std::{
-        io::{BufRead, BufReader},
-        str::FromStr,
-    };
+    parse_proc_io(&std::fs::read_to_string("/proc/self/io")?)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn parse_proc_io(content: &str) -> Result<IOStats, IOStatsError> {
+    use std::str::FromStr;
     let mut io_stats = IOStats::default();
-    let reader = BufReader::new(std::fs::File::open("/proc/self/io")?);
 
-    for line in reader.lines() {
-        let line = line?;
+    for line in content.lines() {
         let mut s = line.split_whitespace();
         if let (Some(field), Some(value)) = (s.next(), s.next()) {
             match field {
+                "rchar:" => io_stats.logical_read_bytes = u64::from_str(value)?,
+                "wchar:" => io_stats.logical_write_bytes = u64::from_str(value)?,
                 "syscr:" => io_stats.read_count = u64::from_str(value)?,
                 "syscw:" => io_stats.write_count = u64::from_str(value)?,
                 "read_bytes:" => io_stats.read_bytes = u64::from_str(value)?,
                 "write_bytes:" => io_stats.write_bytes = u64::from_str(value)?,
+                "cancelled_write_bytes:" => io_stats.cancelled_write_bytes = u64::from_str(value)?,
                 _ => continue,
             }
         }
@@ -99,6 +140,7 @@ fn get_process_io_stats_impl() -> Result<IOStats, IOStatsError> {
         write_count: io_counters.WriteOperationCount,
         read_bytes: io_counters.ReadTransferCount,
         write_bytes: io_counters.WriteTransferCount,
+        ..Default::default()
     })
 }
 
@@ -125,3 +167,30 @@ fn get_process_io_stats_impl() -> Result<IOStats, IOStatsError> {
         ..Default::default()
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_parse_proc_io() {
+        let content = "rchar: 5000
+wchar: 3000
+syscr: 10
+syscw: 6
+read_bytes: 4096
+write_bytes: 8192
+cancelled_write_bytes: 4096
+";
+        let io_stats = parse_proc_io(content).unwrap();
+        assert_eq!(io_stats.logical_read_bytes, 5000);
+        assert_eq!(io_stats.logical_write_bytes, 3000);
+        assert_eq!(io_stats.read_count, 10);
+        assert_eq!(io_stats.write_count, 6);
+        assert_eq!(io_stats.read_bytes, 4096);
+        assert_eq!(io_stats.write_bytes, 8192);
+        assert_eq!(io_stats.cancelled_write_bytes, 4096);
+        assert!(parse_proc_io("rchar: x").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Page cache residency of files, by `mmap` and [mincore].
+//!
+//! Mapping a file doesn't read it, and `mincore` reports whether every page of the mapping
+//! is in the page cache, so checking doesn't change the residency.
+//!
+//! [mincore]: https://man7.org/linux/man-pages/man2/mincore.2.html
+
+use std::{
+    fs::File,
+    io::{Error, Result},
+    os::unix::io::{AsRawFd, RawFd},
+    path::Path,
+};
+
+/// The page cache residency of a file returned by `file_cache_residency`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheResidency {
+    /// the size of the file in bytes.
+    pub size: u64,
+    /// the number of pages of the file.
+    pub pages: u64,
+    /// the number of pages in the page cache.
+    pub resident_pages: u64,
+    /// the bytes of the file in the page cache, which is at most `size`.
+    pub resident: u64,
+}
+
+impl CacheResidency {
+    /// The ratio of pages in the page cache.
+    pub fn ratio(&self) -> f64 {
+        if self.pages == 0 {
+            return 0.0;
+        }
+        self.resident_pages as f64 / self.pages as f64
+    }
+}
+
+/// Get how much of the file at `path` is in the page cache.
+pub fn file_cache_residency(path: impl AsRef<Path>) -> Result<CacheResidency> {
+    let file = File::open(path)?;
+    file_cache_residency_fd(file.as_raw_fd())
+}
+
+/// Get how much of the file opened as `fd` is in the page cache, `fd` must be opened for reading.
+pub fn file_cache_residency_fd(fd: RawFd) -> Result<CacheResidency> {
+    // map at most 1 GiB at a time, so that huge files work with a small address space.
+    const CHUNK: u64 = 1 << 30;
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let size = unsafe { stat.assume_init() }.st_size as u64;
+
+    let mut residency = CacheResidency {
+        size,
+        pages: size.div_ceil(page_size),
+        ..Default::default()
+    };
+    let mut vec = vec![];
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(CHUNK) as usize;
+        vec.resize(len.div_ceil(page_size as usize), 0);
+        unsafe {
+            let addr = libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                offset as libc::off_t,
+            );
+            if addr == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
+            }
+            let ret = libc::mincore(addr, len, vec.as_mut_ptr() as _);
+            let err = Error::last_os_error();
+            libc::munmap(addr, len);
+            if ret != 0 {
+                return Err(err);
+            }
+        }
+        residency.resident_pages += vec.iter().filter(|&&page| page & 1 != 0).count() as u64;
+        offset += len as u64;
+    }
+    // the last page may be partial.
+    residency.resident = (residency.resident_pages * page_size).min(size);
+    Ok(residency)
+}
+
+/// Get the page cache residency of all regular files current process has open,
+/// every file is reported once no matter how many times it's opened.
+///
+/// Files which can't be opened for reading again are skipped.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn open_files_cache_residency() -> Result<Vec<(std::path::PathBuf, CacheResidency)>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut files = vec![];
+    let mut seen = std::collections::HashSet::new();
+    for entry in std::fs::read_dir("/proc/self/fd")? {
+        let link = entry?.path();
+        // follow the link, so that FIFOs and sockets are skipped without being opened.
+        let metadata = match std::fs::metadata(&link) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => continue,
+        };
+        if !seen.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+        // open the link instead of using the fd, which may be write-only or closed concurrently.
+        let (path, residency) = match (std::fs::read_link(&link), file_cache_residency(&link)) {
+            (Ok(path), Ok(residency)) => (path, residency),
+            _ => continue,
+        };
+        files.push((path, residency));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_file_cache_residency() {
+        let path = std::env::temp_dir().join(format!("perf_monitor_cache_{}", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&vec![1u8; 1024 * 1024 + 100]).unwrap();
+
+        let residency = file_cache_residency(&path).unwrap();
+        assert_eq!(residency.size, 1024 * 1024 + 100);
+        assert!(residency.pages > 0);
+        assert!(residency.resident_pages <= residency.pages);
+        assert!(residency.resident <= residency.size);
+        // pages just written are in the page cache.
+        assert!(residency.resident > 0);
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let files = open_files_cache_residency().unwrap();
+            let canonical = path.canonicalize().unwrap();
+            assert!(files
+                .iter()
+                .any(|(p, r)| *p == canonical && r.size == residency.size));
+        }
+
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            file_cache_residency(&path).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+}
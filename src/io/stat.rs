@@ -0,0 +1,128 @@
+use super::{get_process_io_stats, IOStats, IOStatsError};
+use std::{mem, time::Instant};
+
+/// IO per second returned by `IOStat::rate`, the fields are the same as `IOStats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IORate {
+    /// read operations per second.
+    pub read_count: f64,
+    /// write operations per second.
+    pub write_count: f64,
+    pub read_bytes: f64,
+    pub write_bytes: f64,
+    pub logical_read_bytes: f64,
+    pub logical_write_bytes: f64,
+    pub cancelled_write_bytes: f64,
+}
+
+/// Counters which go backwards, e.g. reset, count as 0 instead of wrapping around.
+fn saturating_sub(new: &IOStats, old: &IOStats) -> IOStats {
+    IOStats {
+        read_count: new.read_count.saturating_sub(old.read_count),
+        write_count: new.write_count.saturating_sub(old.write_count),
+        read_bytes: new.read_bytes.saturating_sub(old.read_bytes),
+        write_bytes: new.write_bytes.saturating_sub(old.write_bytes),
+        logical_read_bytes: new
+            .logical_read_bytes
+            .saturating_sub(old.logical_read_bytes),
+        logical_write_bytes: new
+            .logical_write_bytes
+            .saturating_sub(old.logical_write_bytes),
+        cancelled_write_bytes: new
+            .cancelled_write_bytes
+            .saturating_sub(old.cancelled_write_bytes),
+    }
+}
+
+fn rate(stats: &IOStats, old_now: Instant, now: Instant) -> IORate {
+    let real_time = now.saturating_duration_since(old_now).as_secs_f64();
+    if real_time == 0.0 {
+        return IORate::default();
+    }
+    let per_sec = |value: u64| value as f64 / real_time;
+    IORate {
+        read_count: per_sec(stats.read_count),
+        write_count: per_sec(stats.write_count),
+        read_bytes: per_sec(stats.read_bytes),
+        write_bytes: per_sec(stats.write_bytes),
+        logical_read_bytes: per_sec(stats.logical_read_bytes),
+        logical_write_bytes: per_sec(stats.logical_write_bytes),
+        cancelled_write_bytes: per_sec(stats.cancelled_write_bytes),
+    }
+}
+
+/// A struct to monitor process io
+pub struct IOStat {
+    now: Instant,
+    stats: IOStats,
+}
+
+impl IOStat {
+    /// return a monitor of current process
+    pub fn cur() -> Result<Self, IOStatsError> {
+        Ok(IOStat {
+            now: Instant::now(),
+            stats: get_process_io_stats()?,
+        })
+    }
+
+    /// return the io from last invoke,
+    /// or when this struct created if it is the first invoke.
+    pub fn io(&mut self) -> Result<IOStats, IOStatsError> {
+        let old_stats = mem::replace(&mut self.stats, get_process_io_stats()?);
+        self.now = Instant::now();
+        Ok(saturating_sub(&self.stats, &old_stats))
+    }
+
+    /// return the io per second from last invoke,
+    /// or when this struct created if it is the first invoke.
+    pub fn rate(&mut self) -> Result<IORate, IOStatsError> {
+        let old_now = self.now;
+        let stats = self.io()?;
+        Ok(rate(&stats, old_now, self.now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate() {
+        let old = IOStats {
+            read_count: 10,
+            read_bytes: 4096,
+            write_bytes: 8192,
+            ..Default::default()
+        };
+        let new = IOStats {
+            read_count: 30,
+            read_bytes: 4096 * 3,
+            // reset
+            write_bytes: 0,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let io = saturating_sub(&new, &old);
+        let rate = rate(&io, now, now + Duration::from_secs(2));
+        assert_eq!(rate.read_count, 10.0);
+        assert_eq!(rate.read_bytes, 4096.0);
+        assert_eq!(rate.write_bytes, 0.0);
+        assert_eq!(super::rate(&io, now, now), IORate::default());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_io_stat() {
+        let mut stat = IOStat::cur().unwrap();
+        let path = std::env::temp_dir().join(format!("perf_monitor_io_{}", std::process::id()));
+        std::fs::write(&path, vec![1u8; 64 * 1024]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let io = stat.io().unwrap();
+        assert!(io.write_count >= 1);
+        assert!(io.logical_write_bytes >= 64 * 1024);
+        let rate = stat.rate().unwrap();
+        assert!(rate.write_bytes >= 0.0);
+    }
+}
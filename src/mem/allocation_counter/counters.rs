@@ -0,0 +1,173 @@
+//! Sharded global counters.
+//!
+//! Every thread updates one of `SHARDS` shards with relaxed atomics and readers sum all shards,
+//! so that allocating threads don't bounce a single cache line. Shards are assigned to threads round-robin.
+//!
+//! The peak and the limits need the inuse bytes on every allocation, which is too expensive to sum,
+//! so a shard flushes its inuse bytes to an approximate total once they drift by `FLUSH_BYTES`.
+//! The approximate total differs from the exact one by less than `SHARDS * FLUSH_BYTES` (1 MiB).
+
+use super::{limit, AllocStats};
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicIsize, AtomicU64, AtomicUsize, Ordering},
+};
+
+const SHARDS: usize = 64;
+const FLUSH_BYTES: isize = 16 << 10;
+const UNASSIGNED: usize = usize::MAX;
+
+// 128 bytes, because some CPUs prefetch cache lines in pairs.
+#[repr(align(128))]
+struct Shard {
+    allocated: AtomicIsize,
+    /// the inuse bytes of this shard which have been added to `APPROX_ALLOCATED`.
+    flushed: AtomicIsize,
+    total_allocated: AtomicU64,
+    allocations: AtomicU64,
+    deallocations: AtomicU64,
+    reallocations: AtomicU64,
+}
+
+impl Shard {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Shard = Shard {
+        allocated: AtomicIsize::new(0),
+        flushed: AtomicIsize::new(0),
+        total_allocated: AtomicU64::new(0),
+        allocations: AtomicU64::new(0),
+        deallocations: AtomicU64::new(0),
+        reallocations: AtomicU64::new(0),
+    };
+}
+
+static SHARD_LIST: [Shard; SHARDS] = [Shard::EMPTY; SHARDS];
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+static APPROX_ALLOCATED: AtomicIsize = AtomicIsize::new(0);
+static PEAK_ALLOCATED: AtomicIsize = AtomicIsize::new(0);
+
+thread_local! {
+    static SHARD: Cell<usize> = const { Cell::new(UNASSIGNED) };
+}
+
+#[inline]
+fn shard() -> &'static Shard {
+    let index = SHARD
+        .try_with(|shard| {
+            if shard.get() == UNASSIGNED {
+                shard.set(NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS);
+            }
+            shard.get()
+        })
+        .unwrap_or(0);
+    &SHARD_LIST[index]
+}
+
+#[inline]
+fn add_allocated(shard: &Shard, delta: isize) {
+    let allocated = shard.allocated.fetch_add(delta, Ordering::Relaxed) + delta;
+    if (allocated - shard.flushed.load(Ordering::Relaxed)).abs() >= FLUSH_BYTES {
+        flush(shard, allocated);
+    }
+}
+
+#[cold]
+fn flush(shard: &Shard, allocated: isize) {
+    // threads sharing the shard may flush concurrently, the swap makes every change added exactly once.
+    let delta = allocated - shard.flushed.swap(allocated, Ordering::Relaxed);
+    let total = APPROX_ALLOCATED.fetch_add(delta, Ordering::Relaxed) + delta;
+    if delta > 0 {
+        PEAK_ALLOCATED.fetch_max(total, Ordering::Relaxed);
+        limit::check_soft_limit(total);
+    }
+}
+
+#[inline]
+pub(super) fn on_alloc(size: usize) {
+    let shard = shard();
+    add_allocated(shard, size as isize);
+    shard
+        .total_allocated
+        .fetch_add(size as u64, Ordering::Relaxed);
+    shard.allocations.fetch_add(1, Ordering::Relaxed);
+}
+
+#[inline]
+pub(super) fn on_dealloc(size: usize) {
+    let shard = shard();
+    add_allocated(shard, -(size as isize));
+    shard.deallocations.fetch_add(1, Ordering::Relaxed);
+}
+
+#[inline]
+pub(super) fn on_realloc(old_size: usize, new_size: usize) {
+    let shard = shard();
+    add_allocated(shard, new_size as isize - old_size as isize);
+    shard
+        .total_allocated
+        .fetch_add(new_size as u64, Ordering::Relaxed);
+    shard.reallocations.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The exact inuse bytes.
+pub(super) fn allocated() -> isize {
+    SHARD_LIST
+        .iter()
+        .map(|shard| shard.allocated.load(Ordering::Relaxed))
+        .sum()
+}
+
+/// The approximate inuse bytes, which is cheap enough to be read on every allocation.
+#[inline]
+pub(super) fn approx_allocated() -> isize {
+    APPROX_ALLOCATED.load(Ordering::Relaxed)
+}
+
+pub(super) fn stats() -> AllocStats {
+    let mut stats = AllocStats::default();
+    for shard in SHARD_LIST.iter() {
+        stats.allocated += shard.allocated.load(Ordering::Relaxed);
+        stats.total_allocated += shard.total_allocated.load(Ordering::Relaxed);
+        stats.allocations += shard.allocations.load(Ordering::Relaxed);
+        stats.deallocations += shard.deallocations.load(Ordering::Relaxed);
+        stats.reallocations += shard.reallocations.load(Ordering::Relaxed);
+    }
+    stats.peak_allocated = PEAK_ALLOCATED.load(Ordering::Relaxed).max(stats.allocated);
+    stats
+}
+
+pub(super) fn reset() {
+    for shard in SHARD_LIST.iter() {
+        shard.allocated.store(0, Ordering::Relaxed);
+        shard.flushed.store(0, Ordering::Relaxed);
+        shard.total_allocated.store(0, Ordering::Relaxed);
+        shard.allocations.store(0, Ordering::Relaxed);
+        shard.deallocations.store(0, Ordering::Relaxed);
+        shard.reallocations.store(0, Ordering::Relaxed);
+    }
+    APPROX_ALLOCATED.store(0, Ordering::Relaxed);
+    PEAK_ALLOCATED.store(0, Ordering::Relaxed);
+}
+
+pub(super) fn reset_peak() -> isize {
+    let allocated = allocated();
+    PEAK_ALLOCATED
+        .swap(allocated, Ordering::Relaxed)
+        .max(allocated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flush() {
+        let shard = Shard::EMPTY;
+        add_allocated(&shard, FLUSH_BYTES - 1);
+        assert_eq!(shard.flushed.load(Ordering::Relaxed), 0);
+        add_allocated(&shard, 1);
+        assert_eq!(shard.flushed.load(Ordering::Relaxed), FLUSH_BYTES);
+        add_allocated(&shard, -FLUSH_BYTES);
+        assert_eq!(shard.flushed.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,108 @@
+//! Stack traces captured inside the allocator.
+
+use std::{ffi::c_void, fmt};
+
+pub(super) const MAX_FRAMES: usize = 64;
+
+/// Return addresses of a stack trace, the first one is the innermost frame.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) struct Frames {
+    ips: [usize; MAX_FRAMES],
+    len: usize,
+}
+
+impl Frames {
+    /// Capture the stack trace of current thread without allocating.
+    #[inline(never)]
+    pub(super) fn capture() -> Self {
+        let mut frames = Frames {
+            ips: [0; MAX_FRAMES],
+            len: 0,
+        };
+        backtrace::trace(|frame| {
+            frames.ips[frames.len] = frame.ip() as usize;
+            frames.len += 1;
+            frames.len < MAX_FRAMES
+        });
+        frames
+    }
+
+    pub(super) fn ips(&self) -> &[usize] {
+        &self.ips[..self.len]
+    }
+
+    /// Resolve all frames but the leading ones inside the allocator.
+    #[cfg(feature = "backtrace")]
+    pub(super) fn resolve(&self) -> Vec<Symbol> {
+        let mut symbols = self
+            .ips()
+            .iter()
+            .flat_map(|&ip| resolve(ip))
+            .collect::<Vec<_>>();
+        if let Some(pos) = symbols.iter().rposition(Symbol::is_allocator) {
+            symbols.drain(..=pos);
+        }
+        symbols
+    }
+}
+
+/// A resolved source line of a stack frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    /// the demangled function name, or the address if it can't be resolved.
+    pub function: String,
+    pub filename: String,
+    pub line: u32,
+}
+
+impl Symbol {
+    /// Whether this frame is the entry of the allocator.
+    #[cfg(feature = "backtrace")]
+    fn is_allocator(&self) -> bool {
+        const PREFIXES: &[&str] = &[
+            "__rust_alloc",
+            "__rust_realloc",
+            "__rg_alloc",
+            "__rg_realloc",
+            "<perf_monitor::mem::allocation_counter::CountingAllocator",
+        ];
+        PREFIXES.iter().any(|p| self.function.starts_with(p))
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.function)?;
+        if !self.filename.is_empty() {
+            write!(f, " at {}:{}", self.filename, self.line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolve the source lines of a return address, the first line is the innermost inlined function.
+pub(super) fn resolve(ip: usize) -> Vec<Symbol> {
+    let mut symbols = vec![];
+    // a return address points to the instruction after the call.
+    backtrace::resolve(ip.saturating_sub(1) as *mut c_void, |symbol| {
+        symbols.push(Symbol {
+            function: symbol
+                .name()
+                .map(|name| format!("{:#}", name))
+                .unwrap_or_else(|| format!("{:#x}", ip)),
+            filename: symbol
+                .filename()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+            line: symbol.lineno().unwrap_or(0),
+        })
+    });
+    if symbols.is_empty() {
+        symbols.push(Symbol {
+            function: format!("{:#x}", ip),
+            filename: String::new(),
+            line: 0,
+        });
+    }
+    symbols
+}
@@ -0,0 +1,132 @@
+//! A log2-bucketed histogram of allocation sizes.
+//!
+//! The bucket `k` (k > 0) covers sizes in `[2^(k-1), 2^k)` and the bucket 0 covers zero-sized allocations.
+//! Buckets are plain atomics, so updating them never allocates or locks.
+
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+
+const BUCKETS: usize = usize::BITS as usize + 1;
+
+/// A bucket of the histogram returned by `CountingAllocator::size_histogram`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeBucket {
+    /// the min allocation size of this bucket (inclusive).
+    pub min_size: usize,
+    /// the max allocation size of this bucket (inclusive).
+    pub max_size: usize,
+    /// the number of allocations and reallocations fall into this bucket (cumulative).
+    pub allocations: u64,
+    /// the approximate number of live objects in this bucket.
+    ///
+    /// Objects allocated before the histogram is enabled or reset are not tracked, but still subtracted
+    /// when they're freed, so it under-counts, and it's clamped at 0 if more are freed than counted.
+    pub live: u64,
+}
+
+struct Bucket {
+    allocations: AtomicU64,
+    live: AtomicI64,
+}
+
+impl Bucket {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Bucket = Bucket {
+        allocations: AtomicU64::new(0),
+        live: AtomicI64::new(0),
+    };
+}
+
+static BUCKET_LIST: [Bucket; BUCKETS] = [Bucket::EMPTY; BUCKETS];
+static ENABLE: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+fn bucket_index(size: usize) -> usize {
+    (usize::BITS - size.leading_zeros()) as usize
+}
+
+fn bucket_range(index: usize) -> (usize, usize) {
+    match index {
+        0 => (0, 0),
+        _ => (
+            1 << (index - 1),
+            usize::MAX >> (usize::BITS as usize - index),
+        ),
+    }
+}
+
+#[inline]
+pub(super) fn is_enable() -> bool {
+    ENABLE.load(Ordering::Relaxed)
+}
+
+pub(super) fn enable() {
+    ENABLE.store(true, Ordering::SeqCst)
+}
+
+pub(super) fn disable() {
+    ENABLE.store(false, Ordering::SeqCst)
+}
+
+#[inline]
+pub(super) fn on_alloc(size: usize) {
+    let bucket = &BUCKET_LIST[bucket_index(size)];
+    bucket.allocations.fetch_add(1, Ordering::Relaxed);
+    bucket.live.fetch_add(1, Ordering::Relaxed);
+}
+
+#[inline]
+pub(super) fn on_dealloc(size: usize) {
+    BUCKET_LIST[bucket_index(size)]
+        .live
+        .fetch_sub(1, Ordering::Relaxed);
+}
+
+#[inline]
+pub(super) fn on_realloc(old_size: usize, new_size: usize) {
+    on_dealloc(old_size);
+    on_alloc(new_size);
+}
+
+pub(super) fn reset() {
+    for bucket in BUCKET_LIST.iter() {
+        bucket.allocations.store(0, Ordering::Relaxed);
+        bucket.live.store(0, Ordering::Relaxed);
+    }
+}
+
+pub(super) fn snapshot() -> Vec<SizeBucket> {
+    BUCKET_LIST
+        .iter()
+        .enumerate()
+        .map(|(index, bucket)| {
+            let (min_size, max_size) = bucket_range(index);
+            SizeBucket {
+                min_size,
+                max_size,
+                allocations: bucket.allocations.load(Ordering::Relaxed),
+                live: bucket.live.load(Ordering::Relaxed).max(0) as u64,
+            }
+        })
+        .filter(|bucket| bucket.allocations > 0 || bucket.live > 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_index() {
+        assert_eq!(bucket_index(0), 0);
+        assert_eq!(bucket_index(1), 1);
+        assert_eq!(bucket_index(2), 2);
+        assert_eq!(bucket_index(3), 2);
+        assert_eq!(bucket_index(4), 3);
+        assert_eq!(bucket_index(usize::MAX), BUCKETS - 1);
+        for index in 0..BUCKETS {
+            let (min_size, max_size) = bucket_range(index);
+            assert_eq!(bucket_index(min_size), index);
+            assert_eq!(bucket_index(max_size), index);
+        }
+    }
+}
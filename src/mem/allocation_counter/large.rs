@@ -0,0 +1,88 @@
+//! A hook fired by allocations of a large size.
+//!
+//! Allocations made by the hook itself don't fire the hook again.
+
+#[cfg(feature = "backtrace")]
+use super::frames::{Frames, Symbol};
+use std::{
+    cell::Cell,
+    fmt, mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const NO_THRESHOLD: usize = usize::MAX;
+
+static THRESHOLD: AtomicUsize = AtomicUsize::new(NO_THRESHOLD);
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A large allocation or reallocation passed to the hook set by `CountingAllocator::set_large_alloc_hook`.
+#[derive(Clone)]
+pub struct LargeAlloc {
+    /// the size of the allocation, or the new size of the reallocation.
+    pub size: usize,
+    /// the old size if it's a reallocation.
+    pub old_size: Option<usize>,
+    /// the id of the allocating thread given by the OS, the same as `ThreadAllocStats::tid`.
+    pub tid: u64,
+    #[cfg(feature = "backtrace")]
+    frames: Frames,
+}
+
+impl LargeAlloc {
+    /// Resolve the backtrace of the allocation, the first one is the innermost frame.
+    #[cfg(feature = "backtrace")]
+    #[cfg_attr(doc, doc(cfg(feature = "backtrace")))]
+    pub fn backtrace(&self) -> Vec<Symbol> {
+        self.frames.resolve()
+    }
+}
+
+impl fmt::Debug for LargeAlloc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LargeAlloc")
+            .field("size", &self.size)
+            .field("old_size", &self.old_size)
+            .field("tid", &self.tid)
+            .finish_non_exhaustive()
+    }
+}
+
+pub(super) fn set_hook(threshold: usize, hook: fn(&LargeAlloc)) {
+    HOOK.store(hook as usize, Ordering::SeqCst);
+    THRESHOLD.store(threshold, Ordering::SeqCst);
+}
+
+pub(super) fn clear_hook() {
+    THRESHOLD.store(NO_THRESHOLD, Ordering::SeqCst);
+}
+
+#[inline]
+pub(super) fn is_large(size: usize) -> bool {
+    size >= THRESHOLD.load(Ordering::Relaxed)
+}
+
+#[cold]
+pub(super) fn fire(size: usize, old_size: Option<usize>) {
+    let hook = HOOK.load(Ordering::SeqCst);
+    if hook == 0 {
+        return;
+    }
+    let hook: fn(&LargeAlloc) = unsafe { mem::transmute(hook) };
+    let _ = IN_HOOK.try_with(|in_hook| {
+        if in_hook.replace(true) {
+            return;
+        }
+        hook(&LargeAlloc {
+            size,
+            old_size,
+            tid: super::thread::os_thread_id(),
+            #[cfg(feature = "backtrace")]
+            frames: Frames::capture(),
+        });
+        in_hook.set(false);
+    });
+}
@@ -0,0 +1,237 @@
+//! Sampled latency of allocator calls.
+//!
+//! Every thread times one of `interval` calls, the latency is recorded in a log2-bucketed histogram
+//! of nanoseconds per operation. The bucket `k` (k > 0) covers `[2^(k-1), 2^k)` nanoseconds.
+//! Calls not sampled cost a relaxed load and a thread local countdown.
+
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+const BUCKETS: usize = u64::BITS as usize + 1;
+
+/// An operation of the allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AllocOp {
+    /// `alloc` and `alloc_zeroed`.
+    Alloc = 0,
+    Dealloc = 1,
+    Realloc = 2,
+}
+
+impl AllocOp {
+    pub const ALL: [AllocOp; 3] = [AllocOp::Alloc, AllocOp::Dealloc, AllocOp::Realloc];
+}
+
+/// A bucket of `LatencyHistogram`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyBucket {
+    /// the min latency of this bucket in nanoseconds (inclusive).
+    pub min_nanos: u64,
+    /// the max latency of this bucket in nanoseconds (inclusive).
+    pub max_nanos: u64,
+    /// the number of samples fall into this bucket.
+    pub count: u64,
+}
+
+/// The latency of sampled calls of an operation returned by `CountingAllocator::latency_histogram`.
+///
+/// Fields are read one by one, so they may be slightly inconsistent with each other
+/// when other threads are allocating.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// the number of sampled calls.
+    pub samples: u64,
+    /// the sum of latency of sampled calls in nanoseconds.
+    pub total_nanos: u64,
+    /// the max latency of sampled calls in nanoseconds.
+    pub max_nanos: u64,
+    /// the non-empty buckets, ordered by latency.
+    pub buckets: Vec<LatencyBucket>,
+}
+
+impl LatencyHistogram {
+    /// The mean latency of sampled calls, `None` if there's no sample.
+    pub fn mean(&self) -> Option<Duration> {
+        (self.samples > 0).then(|| Duration::from_nanos(self.total_nanos / self.samples))
+    }
+
+    /// The max latency of sampled calls.
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_nanos)
+    }
+
+    /// The upper bound of the `p`th percentile (0.0 ~ 100.0) of latency, `None` if there's no sample.
+    ///
+    /// It's the max latency of the bucket the percentile falls into, so it's at most twice the real one.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.samples == 0 {
+            return None;
+        }
+        let rank = ((p.clamp(0.0, 100.0) / 100.0 * self.samples as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        let bucket = self.buckets.iter().find(|bucket| {
+            seen += bucket.count;
+            seen >= rank
+        })?;
+        Some(Duration::from_nanos(bucket.max_nanos.min(self.max_nanos)))
+    }
+}
+
+struct Histogram {
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Histogram {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Histogram = {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Histogram {
+            total_nanos: ZERO,
+            max_nanos: ZERO,
+            buckets: [ZERO; BUCKETS],
+        }
+    };
+}
+
+static HISTOGRAMS: [Histogram; 3] = [Histogram::EMPTY; 3];
+/// Sample one of `INTERVAL` calls per thread, 0 means disable.
+static INTERVAL: AtomicU32 = AtomicU32::new(0);
+
+thread_local! {
+    static COUNTDOWN: Cell<u32> = const { Cell::new(0) };
+}
+
+#[inline]
+fn bucket_index(nanos: u64) -> usize {
+    (u64::BITS - nanos.leading_zeros()) as usize
+}
+
+fn bucket_range(index: usize) -> (u64, u64) {
+    match index {
+        0 => (0, 0),
+        _ => (1 << (index - 1), u64::MAX >> (u64::BITS as usize - index)),
+    }
+}
+
+pub(super) fn is_enable() -> bool {
+    INTERVAL.load(Ordering::Relaxed) > 0
+}
+
+pub(super) fn enable(interval: u32) {
+    INTERVAL.store(interval.max(1), Ordering::SeqCst)
+}
+
+pub(super) fn disable() {
+    INTERVAL.store(0, Ordering::SeqCst)
+}
+
+/// Return the start time if current call should be sampled.
+#[inline]
+pub(super) fn start() -> Option<Instant> {
+    let interval = INTERVAL.load(Ordering::Relaxed);
+    if interval == 0 {
+        return None;
+    }
+    let sampled = COUNTDOWN
+        .try_with(|countdown| match countdown.get() {
+            0 | 1 => {
+                countdown.set(interval);
+                true
+            }
+            n => {
+                countdown.set(n - 1);
+                false
+            }
+        })
+        .unwrap_or(false);
+    sampled.then(Instant::now)
+}
+
+#[cold]
+pub(super) fn record(op: AllocOp, start: Instant) {
+    let nanos = start.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+    let histogram = &HISTOGRAMS[op as usize];
+    histogram.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+    histogram.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    // released after the total and the max, see `snapshot`.
+    histogram.buckets[bucket_index(nanos)].fetch_add(1, Ordering::Release);
+}
+
+pub(super) fn reset() {
+    for histogram in HISTOGRAMS.iter() {
+        histogram.total_nanos.store(0, Ordering::Relaxed);
+        histogram.max_nanos.store(0, Ordering::Relaxed);
+        for bucket in histogram.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+pub(super) fn snapshot(op: AllocOp) -> LatencyHistogram {
+    let histogram = &HISTOGRAMS[op as usize];
+    // buckets are updated last, so read them first, then the total and the max include every sample
+    // counted. Samples recorded in between may be in the total but not counted, over-reporting the mean a bit.
+    let buckets = histogram
+        .buckets
+        .iter()
+        .enumerate()
+        .map(|(index, count)| {
+            let (min_nanos, max_nanos) = bucket_range(index);
+            LatencyBucket {
+                min_nanos,
+                max_nanos,
+                count: count.load(Ordering::Acquire),
+            }
+        })
+        .filter(|bucket| bucket.count > 0)
+        .collect::<Vec<_>>();
+    let total_nanos = histogram.total_nanos.load(Ordering::Relaxed);
+    let max_nanos = histogram.max_nanos.load(Ordering::Relaxed);
+    LatencyHistogram {
+        samples: buckets.iter().map(|bucket| bucket.count).sum(),
+        total_nanos,
+        max_nanos,
+        buckets,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let bucket = |index, count| {
+            let (min_nanos, max_nanos) = bucket_range(index);
+            LatencyBucket {
+                min_nanos,
+                max_nanos,
+                count,
+            }
+        };
+        assert_eq!(bucket_index(0), 0);
+        assert_eq!(bucket_index(1000), 10);
+        assert_eq!(bucket_index(u64::MAX), BUCKETS - 1);
+
+        let histogram = LatencyHistogram {
+            samples: 100,
+            total_nanos: 100 * 300,
+            max_nanos: 5000,
+            buckets: vec![bucket(8, 90), bucket(10, 9), bucket(13, 1)],
+        };
+        assert_eq!(histogram.mean(), Some(Duration::from_nanos(300)));
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_nanos(255)));
+        assert_eq!(histogram.percentile(99.0), Some(Duration::from_nanos(1023)));
+        assert_eq!(
+            histogram.percentile(100.0),
+            Some(Duration::from_nanos(5000))
+        );
+        assert_eq!(LatencyHistogram::default().percentile(50.0), None);
+    }
+}
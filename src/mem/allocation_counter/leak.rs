@@ -0,0 +1,206 @@
+//! Leak tracking, which records every live allocation with its size and backtrace.
+//!
+//! It's expensive, every allocation captures a backtrace and every deallocation locks a shard
+//! of the records, so it's meant for tests and staging builds.
+
+use super::{
+    bookkeeping,
+    frames::{Frames, Symbol},
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+const SHARDS: usize = 64;
+
+/// A live allocation, returned by `take` so that it can be restored if the allocation is not freed.
+pub(super) struct Record {
+    size: usize,
+    generation: u64,
+    frames: Frames,
+}
+
+type Shard = Mutex<Option<HashMap<usize, Record>>>;
+
+static ENABLE: AtomicBool = AtomicBool::new(false);
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SHARD: Shard = Mutex::new(None);
+static RECORDS: [Shard; SHARDS] = [EMPTY_SHARD; SHARDS];
+
+fn shard(ptr: usize) -> &'static Shard {
+    &RECORDS[(ptr >> 4) % SHARDS]
+}
+
+/// A mark returned by `CountingAllocator::leak_checkpoint`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LeakCheckpoint(u64);
+
+/// Live allocations from the same call site.
+#[derive(Debug, Clone)]
+pub struct LeakSite {
+    /// the number of live allocations.
+    pub count: usize,
+    /// the sum of bytes of live allocations.
+    pub bytes: usize,
+    /// the backtrace of the call site, the first one is the innermost frame.
+    pub backtrace: Vec<Symbol>,
+}
+
+/// Live allocations grouped by call site, returned by `CountingAllocator::leak_report`.
+#[derive(Debug, Clone, Default)]
+pub struct LeakReport {
+    /// the number of live allocations.
+    pub count: usize,
+    /// the sum of bytes of live allocations.
+    pub bytes: usize,
+    /// call sites ordered by bytes descending.
+    pub sites: Vec<LeakSite>,
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} bytes in {} live allocations from {} call sites",
+            self.bytes,
+            self.count,
+            self.sites.len()
+        )?;
+        for site in &self.sites {
+            writeln!(f, "\n{} bytes in {} allocations", site.bytes, site.count)?;
+            for symbol in &site.backtrace {
+                writeln!(f, "    {}", symbol)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+pub(super) fn is_enable() -> bool {
+    ENABLE.load(Ordering::Relaxed)
+}
+
+/// Whether there are live records to be removed on deallocation.
+#[inline]
+pub(super) fn has_live() -> bool {
+    LIVE.load(Ordering::Relaxed) > 0
+}
+
+pub(super) fn start() {
+    ENABLE.store(true, Ordering::SeqCst);
+}
+
+pub(super) fn stop() {
+    ENABLE.store(false, Ordering::SeqCst);
+    bookkeeping(|| {
+        for shard in RECORDS.iter() {
+            if let Some(records) = shard.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                LIVE.fetch_sub(records.len(), Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+pub(super) fn checkpoint() -> LeakCheckpoint {
+    LeakCheckpoint(GENERATION.fetch_add(1, Ordering::SeqCst) + 1)
+}
+
+#[cold]
+pub(super) fn on_alloc(ptr: *mut u8, size: usize) {
+    bookkeeping(|| {
+        let record = Record {
+            size,
+            generation: GENERATION.load(Ordering::Relaxed),
+            frames: Frames::capture(),
+        };
+        let replaced = shard(ptr as usize)
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(HashMap::new)
+            .insert(ptr as usize, record);
+        if replaced.is_none() {
+            LIVE.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Remove the record of `ptr`, which must be called before `ptr` is freed,
+/// otherwise another thread may get the same address and its record would be removed.
+#[cold]
+pub(super) fn take(ptr: *mut u8) -> Option<Record> {
+    // Allocations made inside `bookkeeping` are never tracked, so it's fine to skip deallocations made there.
+    bookkeeping(|| {
+        let removed = shard(ptr as usize)
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_mut()
+            .and_then(|records| records.remove(&(ptr as usize)));
+        if removed.is_some() {
+            LIVE.fetch_sub(1, Ordering::Relaxed);
+        }
+        removed
+    })
+    .flatten()
+}
+
+/// Put back a record taken by `take`, e.g. when a reallocation fails.
+#[cold]
+pub(super) fn restore(ptr: *mut u8, record: Record) {
+    bookkeeping(|| {
+        let replaced = shard(ptr as usize)
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(HashMap::new)
+            .insert(ptr as usize, record);
+        if replaced.is_none() {
+            LIVE.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+}
+
+pub(super) fn report(since: LeakCheckpoint) -> LeakReport {
+    bookkeeping(|| {
+        let mut sites = HashMap::<Frames, (usize, usize)>::new();
+        for shard in RECORDS.iter() {
+            let records = shard.lock().unwrap_or_else(|e| e.into_inner());
+            for record in records.iter().flat_map(|r| r.values()) {
+                if record.generation >= since.0 {
+                    let site = sites.entry(record.frames).or_default();
+                    site.0 += 1;
+                    site.1 += record.size;
+                }
+            }
+        }
+        let mut sites = sites
+            .into_iter()
+            .map(|(frames, (count, bytes))| LeakSite {
+                count,
+                bytes,
+                backtrace: frames.resolve(),
+            })
+            .collect::<Vec<_>>();
+        sites.sort_by_key(|site| std::cmp::Reverse(site.bytes));
+        LeakReport {
+            count: sites.iter().map(|site| site.count).sum(),
+            bytes: sites.iter().map(|site| site.bytes).sum(),
+            sites,
+        }
+    })
+    .unwrap_or_default()
+}
+
+extern "C" fn print_report() {
+    eprintln!("{}", report(LeakCheckpoint::default()));
+}
+
+pub(super) fn report_at_exit() {
+    unsafe { libc::atexit(print_report) };
+}
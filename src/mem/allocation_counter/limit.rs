@@ -0,0 +1,142 @@
+//! Soft and hard limits of the inuse bytes.
+//!
+//! Limits are checked against the approximate total of the global counters,
+//! so they take effect only if the counter is enable and they are precise to 1 MiB.
+//! The check and the update of the counter are not a single atomic operation,
+//! concurrent allocations may exceed the hard limit slightly.
+
+use std::{
+    alloc::Layout,
+    cell::Cell,
+    mem,
+    sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering},
+};
+
+const NO_LIMIT: isize = isize::MAX;
+
+static SOFT_LIMIT: AtomicIsize = AtomicIsize::new(NO_LIMIT);
+static SOFT_FIRED: AtomicBool = AtomicBool::new(false);
+static SOFT_CALLBACK: AtomicUsize = AtomicUsize::new(0);
+static HARD_LIMIT: AtomicIsize = AtomicIsize::new(NO_LIMIT);
+static HARD_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+pub(super) fn set_soft_limit(limit: usize, callback: fn()) {
+    SOFT_CALLBACK.store(callback as usize, Ordering::SeqCst);
+    SOFT_FIRED.store(false, Ordering::SeqCst);
+    SOFT_LIMIT.store(limit.min(NO_LIMIT as usize) as isize, Ordering::SeqCst);
+}
+
+pub(super) fn clear_soft_limit() {
+    SOFT_LIMIT.store(NO_LIMIT, Ordering::SeqCst);
+}
+
+pub(super) fn set_hard_limit(limit: usize) {
+    HARD_LIMIT.store(limit.min(NO_LIMIT as usize) as isize, Ordering::SeqCst);
+}
+
+pub(super) fn clear_hard_limit() {
+    HARD_LIMIT.store(NO_LIMIT, Ordering::SeqCst);
+}
+
+pub(super) fn set_hard_limit_hook(hook: Option<fn(Layout)>) {
+    HARD_HOOK.store(hook.map_or(0, |hook| hook as usize), Ordering::SeqCst);
+}
+
+/// Check whether growing `allocated` by `grow` bytes exceeds the hard limit,
+/// the hook is invoked if so.
+///
+/// Allocations made by the hook itself are not limited, otherwise they would invoke the hook again
+/// until the stack overflows.
+#[inline]
+pub(super) fn exceeds_hard_limit(allocated: isize, grow: usize, layout: Layout) -> bool {
+    let limit = HARD_LIMIT.load(Ordering::Relaxed);
+    if limit == NO_LIMIT || allocated.saturating_add(grow as isize) <= limit {
+        return false;
+    }
+    exceeds_hard_limit_slow(layout)
+}
+
+#[cold]
+fn exceeds_hard_limit_slow(layout: Layout) -> bool {
+    let hook = HARD_HOOK.load(Ordering::SeqCst);
+    if hook == 0 {
+        return true;
+    }
+    let hook: fn(Layout) = unsafe { mem::transmute(hook) };
+    IN_HOOK
+        .try_with(|in_hook| {
+            if in_hook.replace(true) {
+                return false;
+            }
+            hook(layout);
+            in_hook.set(false);
+            true
+        })
+        .unwrap_or(true)
+}
+
+/// Invoke the callback if `allocated` crosses the soft limit for the first time.
+#[inline]
+pub(super) fn check_soft_limit(allocated: isize) {
+    if allocated <= SOFT_LIMIT.load(Ordering::Relaxed) || SOFT_FIRED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let callback = SOFT_CALLBACK.load(Ordering::SeqCst);
+    if callback != 0 {
+        let callback: fn() = unsafe { mem::transmute(callback) };
+        callback();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // limits far beyond the real inuse bytes, so that other tests are not affected.
+    const LIMIT: usize = NO_LIMIT as usize / 2;
+
+    #[test]
+    fn test_soft_limit() {
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+        set_soft_limit(LIMIT, || {
+            FIRED.fetch_add(1, Ordering::SeqCst);
+        });
+        check_soft_limit(LIMIT as isize);
+        assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+        check_soft_limit(LIMIT as isize + 1);
+        check_soft_limit(LIMIT as isize + 2);
+        assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+        clear_soft_limit();
+    }
+
+    #[test]
+    fn test_hard_limit() {
+        static HOOKED: AtomicUsize = AtomicUsize::new(0);
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        set_hard_limit(LIMIT);
+        set_hard_limit_hook(Some(|layout| {
+            HOOKED.store(layout.size(), Ordering::SeqCst);
+        }));
+        assert!(!exceeds_hard_limit(LIMIT as isize - 16, 16, layout));
+        assert_eq!(HOOKED.load(Ordering::SeqCst), 0);
+        assert!(exceeds_hard_limit(LIMIT as isize - 15, 16, layout));
+        assert_eq!(HOOKED.load(Ordering::SeqCst), 16);
+
+        // a hook which allocates, e.g. by `format!`, goes through the check again.
+        static NESTED: AtomicUsize = AtomicUsize::new(0);
+        set_hard_limit_hook(Some(|layout| {
+            NESTED.fetch_add(1, Ordering::SeqCst);
+            assert!(!exceeds_hard_limit(LIMIT as isize, 16, layout));
+            std::hint::black_box(format!("{:?}", layout));
+        }));
+        assert!(exceeds_hard_limit(LIMIT as isize, 16, layout));
+        assert_eq!(NESTED.load(Ordering::SeqCst), 1);
+        set_hard_limit_hook(None);
+        clear_hard_limit();
+        assert!(!exceeds_hard_limit(LIMIT as isize, 16, layout));
+    }
+}
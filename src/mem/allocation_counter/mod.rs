@@ -0,0 +1,981 @@
+//! The counting allocator and the optional statistics it collects.
+//!
+//! Everything here runs inside the allocator, so thread locals must be `const` initialized
+//! without destructors, which are always safe to be accessed there: registering a destructor
+//! may allocate, and a thread local can't be accessed once its destructor has run,
+//! while the allocator is still called by destructors of other thread locals.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+mod counters;
+#[cfg(feature = "backtrace")]
+mod frames;
+mod histogram;
+mod large;
+mod latency;
+#[cfg(feature = "leak_tracker")]
+mod leak;
+mod limit;
+#[cfg(feature = "heap_profiler")]
+mod profiler;
+mod scope;
+mod tag;
+mod thread;
+
+#[cfg(feature = "backtrace")]
+pub use frames::Symbol;
+pub use histogram::SizeBucket;
+pub use large::LargeAlloc;
+pub use latency::{AllocOp, LatencyBucket, LatencyHistogram};
+#[cfg(feature = "leak_tracker")]
+pub use leak::{LeakCheckpoint, LeakReport, LeakSite};
+pub use scope::{measure_alloc, AllocScope, ScopeStats};
+pub use tag::{current_tag, with_tag, Tag, TagGuard, TagStats, MAX_TAGS};
+pub(crate) use thread::os_thread_id;
+pub use thread::{ThreadAllocStats, MAX_TRACKED_THREADS};
+
+pub const MIN_ALIGN: usize = 16; // module `sys_common` is private. https://doc.rust-lang.org/src/std/sys_common/alloc.rs.html#28
+
+static ENABLE: AtomicBool = AtomicBool::new(false);
+
+#[cfg(any(feature = "heap_profiler", feature = "leak_tracker"))]
+thread_local! {
+    static IN_BOOKKEEPING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Run `f` with the heap profiler or the leak tracker marked as updating its records on current thread,
+/// return `None` if one of them is doing so already.
+///
+/// Allocations and deallocations made inside are skipped by both, otherwise a thread holding the lock
+/// of one would wait for the lock of the other, which may be held by a thread waiting for the former.
+#[cfg(any(feature = "heap_profiler", feature = "leak_tracker"))]
+fn bookkeeping<R>(f: impl FnOnce() -> R) -> Option<R> {
+    IN_BOOKKEEPING
+        .try_with(|in_bookkeeping| {
+            if in_bookkeeping.replace(true) {
+                return None;
+            }
+            let ret = f();
+            in_bookkeeping.set(false);
+            Some(ret)
+        })
+        .ok()
+        .flatten()
+}
+
+/// Whether current thread is inside `bookkeeping`, which is also true if it can't be known.
+#[cfg(feature = "heap_profiler")]
+fn in_bookkeeping() -> bool {
+    IN_BOOKKEEPING
+        .try_with(|in_bookkeeping| in_bookkeeping.get())
+        .unwrap_or(true)
+}
+
+/// A snapshot of the counters returned by `CountingAllocator::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// the inuse bytes allocated by rust, the same as `CountingAllocator::get_allocated`.
+    pub allocated: isize,
+    /// the high-water mark of `allocated` since the last reset.
+    ///
+    /// It's tracked by the approximate total (see `CountingAllocator`), so it may be off by less than
+    /// 1 MiB (64 shards of 16 KiB) either way, e.g. a short-lived spike smaller than that may be missed.
+    /// It's never less than `allocated` of the same snapshot.
+    pub peak_allocated: isize,
+    /// bytes ever allocated (cumulative), including the new size of reallocations.
+    pub total_allocated: u64,
+    /// the number of allocations (cumulative).
+    pub allocations: u64,
+    /// the number of deallocations (cumulative).
+    pub deallocations: u64,
+    /// the number of reallocations (cumulative).
+    pub reallocations: u64,
+}
+
+/// An allocator tracks inuse allocated bytes, which forwards to the wrapped allocator `A`.
+///
+/// ```ignore
+/// #[global_allocator]
+/// static _COUNTER: CountingAllocator<Jemalloc> = CountingAllocator::new(Jemalloc);
+/// ```
+///
+/// All counters are global no matter which allocator is wrapped,
+/// so they are read by associated functions like `CountingAllocator::get_allocated()`.
+/// They are sharded by thread to avoid contention, reading them sums up all shards.
+/// The peak and the limits are tracked by an approximate total updated in batches,
+/// which differs from the exact inuse bytes by less than 1 MiB.
+///
+/// The counter is disable by default. Please enable it by `CountingAllocator::enable()` then call `CountingAllocator::get_allocated()` will return the bytes inused.
+/// `CountingAllocator::stats()` returns more counters such as the number of allocations and the peak inuse bytes.
+/// Everything below, e.g. limits, per-thread counters, tags and the heap profiler, takes effect only if the counter is enable.
+///
+/// Per-thread counters are optional, enable them by `CountingAllocator::enable_thread_counters()`
+/// additionally. Memory freed by another thread is credited to the thread which frees it.
+///
+/// The size histogram is optional too, enable it by `CountingAllocator::enable_histogram()`.
+///
+/// Latency sampling is optional too, enable it by `CountingAllocator::enable_latency_sampling()`.
+///
+/// `CountingAllocator::set_large_alloc_hook()` catches allocations bigger than a threshold where they happen.
+///
+/// A soft limit and a hard limit of the inuse bytes can be set by `CountingAllocator::set_soft_limit()`
+/// and `CountingAllocator::set_hard_limit()`, see their documents for details.
+///
+/// With the `heap_profiler` feature, `CountingAllocator::start_heap_profiling()` samples allocations
+/// with their backtraces and `CountingAllocator::write_heap_profile()` writes the live samples in pprof format.
+///
+/// With the `leak_tracker` feature, `CountingAllocator::start_leak_tracking()` records every live allocation
+/// with its backtrace and `CountingAllocator::leak_report()` groups them by call site.
+///
+/// An allocator built by `CountingAllocator::with_tagging()` charges allocations to the tag
+/// of current thread (see `with_tag`), `CountingAllocator::tag_stats()` returns the per-tag counters.
+pub struct CountingAllocator<A: GlobalAlloc = System> {
+    inner: A,
+    tagging: bool,
+}
+
+/// A `CountingAllocator` wrapping `System`, so that `static A: CountingAllocator = CountingAllocator;`
+/// written before the allocator was generic still compiles.
+#[allow(non_upper_case_globals)]
+pub const CountingAllocator: CountingAllocator = CountingAllocator::new(System);
+
+impl<A: GlobalAlloc> CountingAllocator<A> {
+    /// Wrap the allocator `inner`.
+    pub const fn new(inner: A) -> Self {
+        CountingAllocator {
+            inner,
+            tagging: false,
+        }
+    }
+
+    /// Charge allocations to the tag of current thread, and credit frees to the tag which made the allocation.
+    ///
+    /// Every block is prefixed with a header of `max(align, 16)` bytes to remember its tag,
+    /// the header is not counted as inuse bytes.
+    pub const fn with_tagging(mut self) -> Self {
+        self.tagging = true;
+        self
+    }
+}
+
+impl CountingAllocator {
+    /// Get the inuse bytes allocated by rust.
+    pub fn get_allocated() -> isize {
+        counters::allocated()
+    }
+
+    /// Get a snapshot of all counters.
+    ///
+    /// Counters are read one by one, so they may be slightly inconsistent with each other
+    /// when other threads are allocating.
+    pub fn stats() -> AllocStats {
+        counters::stats()
+    }
+
+    /// Check whether the counter is enable.
+    pub fn is_enable() -> bool {
+        ENABLE.load(Ordering::SeqCst)
+    }
+
+    /// Reset all counters.
+    pub fn reset() {
+        counters::reset()
+    }
+
+    /// Reset the peak inuse bytes to the current inuse bytes and return the old peak.
+    ///
+    /// e.g. call it before a batch of requests and read `stats().peak_allocated` after it
+    /// to measure the peak memory of the batch.
+    ///
+    /// The old peak may be off by less than 1 MiB like `AllocStats::peak_allocated`,
+    /// the new peak starts from the exact inuse bytes.
+    pub fn reset_peak() -> isize {
+        counters::reset_peak()
+    }
+
+    /// Enable the counter.
+    pub fn enable() {
+        ENABLE.store(true, Ordering::SeqCst)
+    }
+
+    /// Disable the counter.
+    pub fn disable() {
+        ENABLE.store(false, Ordering::SeqCst)
+    }
+
+    /// Check whether the per-thread counters are enable.
+    pub fn is_thread_counters_enable() -> bool {
+        thread::is_enable()
+    }
+
+    /// Enable the per-thread counters.
+    pub fn enable_thread_counters() {
+        thread::enable()
+    }
+
+    /// Disable the per-thread counters.
+    pub fn disable_thread_counters() {
+        thread::disable()
+    }
+
+    /// Get the counters of current thread.
+    ///
+    /// Return `None` if current thread is not tracked,
+    /// e.g. there are more than `MAX_TRACKED_THREADS` live threads.
+    pub fn current_thread_stats() -> Option<ThreadAllocStats> {
+        thread::current_thread_stats()
+    }
+
+    /// Get the counters of all live threads which are tracked.
+    pub fn all_threads_stats() -> Vec<ThreadAllocStats> {
+        thread::all_threads_stats()
+    }
+
+    /// Get the sum of counters flushed by exited threads.
+    pub fn exited_threads_stats() -> ThreadAllocStats {
+        thread::exited_threads_stats()
+    }
+
+    /// Check whether the size histogram is enable.
+    pub fn is_histogram_enable() -> bool {
+        histogram::is_enable()
+    }
+
+    /// Enable the size histogram.
+    pub fn enable_histogram() {
+        histogram::enable()
+    }
+
+    /// Disable the size histogram.
+    pub fn disable_histogram() {
+        histogram::disable()
+    }
+
+    /// Reset the size histogram.
+    pub fn reset_histogram() {
+        histogram::reset()
+    }
+
+    /// Get a snapshot of the non-empty buckets of the size histogram, ordered by size.
+    pub fn size_histogram() -> Vec<SizeBucket> {
+        histogram::snapshot()
+    }
+
+    /// Check whether the latency sampling is enable.
+    pub fn is_latency_sampling_enable() -> bool {
+        latency::is_enable()
+    }
+
+    /// Time one of `interval` calls of every thread and record the latency per operation.
+    ///
+    /// Calls not sampled only decrease a thread local countdown, 1000 is a reasonable interval for production.
+    pub fn enable_latency_sampling(interval: u32) {
+        latency::enable(interval)
+    }
+
+    /// Disable the latency sampling.
+    pub fn disable_latency_sampling() {
+        latency::disable()
+    }
+
+    /// Reset the latency histograms of all operations.
+    pub fn reset_latency_histograms() {
+        latency::reset()
+    }
+
+    /// Get a snapshot of the latency histogram of `op`.
+    pub fn latency_histogram(op: AllocOp) -> LatencyHistogram {
+        latency::snapshot(op)
+    }
+
+    /// Set a soft limit of the inuse bytes, `callback` is invoked once when the inuse bytes
+    /// exceed `limit` for the first time, e.g. to drop caches.
+    ///
+    /// The callback runs inside the allocator on the allocating thread, so it should be quick.
+    /// It may allocate, but it won't be invoked recursively. Setting the limit again re-arms the callback.
+    pub fn set_soft_limit(limit: usize, callback: fn()) {
+        limit::set_soft_limit(limit, callback)
+    }
+
+    /// Remove the soft limit.
+    pub fn clear_soft_limit() {
+        limit::clear_soft_limit()
+    }
+
+    /// Set a hard limit of the inuse bytes, an allocation or reallocation which makes the inuse bytes
+    /// exceed `limit` fails with null, so that `std::alloc::handle_alloc_error` runs instead of
+    /// the OOM killer of the OS.
+    pub fn set_hard_limit(limit: usize) {
+        limit::set_hard_limit(limit)
+    }
+
+    /// Remove the hard limit.
+    pub fn clear_hard_limit() {
+        limit::clear_hard_limit()
+    }
+
+    /// Set a hook which is invoked with the layout of the allocation before it fails because of the hard limit.
+    ///
+    /// Like the soft limit callback, the hook runs inside the allocator.
+    /// Allocations made by the hook itself are not limited, so it may allocate, e.g. to log.
+    pub fn set_hard_limit_hook(hook: Option<fn(Layout)>) {
+        limit::set_hard_limit_hook(hook)
+    }
+
+    /// Get the counters of `tag`, which are updated by tagging allocators.
+    pub fn tag_stats(tag: Tag) -> TagStats {
+        tag::stats(tag)
+    }
+
+    /// Get the counters of all tags which have ever allocated, ordered by tag.
+    pub fn all_tag_stats() -> Vec<(Tag, TagStats)> {
+        tag::all_stats()
+    }
+
+    /// Set a hook which is invoked with an allocation or a growing reallocation of at least `threshold` bytes,
+    /// e.g. 64 MiB catches unexpected huge `Vec` growth.
+    ///
+    /// The hook runs inside the allocator on the allocating thread after the allocation succeeds.
+    /// It may allocate, but it won't be invoked recursively.
+    /// With the `backtrace` feature, `LargeAlloc::backtrace()` resolves where the allocation happens.
+    pub fn set_large_alloc_hook(threshold: usize, hook: fn(&LargeAlloc)) {
+        large::set_hook(threshold, hook)
+    }
+
+    /// Remove the large allocation hook.
+    pub fn clear_large_alloc_hook() {
+        large::clear_hook()
+    }
+
+    /// Check whether the heap profiler is sampling.
+    #[cfg(feature = "heap_profiler")]
+    #[cfg_attr(doc, doc(cfg(feature = "heap_profiler")))]
+    pub fn is_heap_profiling() -> bool {
+        profiler::is_enable()
+    }
+
+    /// Start sampling an allocation about every `sample_interval` bytes.
+    ///
+    /// A backtrace is captured for every sampled allocation,
+    /// 512 KiB is a reasonable interval for production.
+    #[cfg(feature = "heap_profiler")]
+    #[cfg_attr(doc, doc(cfg(feature = "heap_profiler")))]
+    pub fn start_heap_profiling(sample_interval: usize) {
+        profiler::start(sample_interval)
+    }
+
+    /// Stop sampling and drop all samples.
+    #[cfg(feature = "heap_profiler")]
+    #[cfg_attr(doc, doc(cfg(feature = "heap_profiler")))]
+    pub fn stop_heap_profiling() {
+        profiler::stop()
+    }
+
+    /// Write the sampled allocations which are still live as a heap profile in pprof format,
+    /// which can be viewed by `go tool pprof`.
+    ///
+    /// The profile is an uncompressed protobuf with `inuse_objects` and `inuse_space` sample types,
+    /// scaled by the sampling probability.
+    #[cfg(feature = "heap_profiler")]
+    #[cfg_attr(doc, doc(cfg(feature = "heap_profiler")))]
+    pub fn write_heap_profile(mut w: impl std::io::Write) -> std::io::Result<()> {
+        profiler::write_heap_profile(&mut w)
+    }
+
+    /// Check whether the leak tracker is recording.
+    #[cfg(feature = "leak_tracker")]
+    #[cfg_attr(doc, doc(cfg(feature = "leak_tracker")))]
+    pub fn is_leak_tracking() -> bool {
+        leak::is_enable()
+    }
+
+    /// Start recording every allocation with its size and backtrace until it's freed.
+    ///
+    /// It slows down every allocation and deallocation significantly, don't use it in production.
+    #[cfg(feature = "leak_tracker")]
+    #[cfg_attr(doc, doc(cfg(feature = "leak_tracker")))]
+    pub fn start_leak_tracking() {
+        leak::start()
+    }
+
+    /// Stop recording and drop all records.
+    #[cfg(feature = "leak_tracker")]
+    #[cfg_attr(doc, doc(cfg(feature = "leak_tracker")))]
+    pub fn stop_leak_tracking() {
+        leak::stop()
+    }
+
+    /// Mark a checkpoint, `CountingAllocator::leak_report_since()` reports allocations made after it.
+    #[cfg(feature = "leak_tracker")]
+    #[cfg_attr(doc, doc(cfg(feature = "leak_tracker")))]
+    pub fn leak_checkpoint() -> LeakCheckpoint {
+        leak::checkpoint()
+    }
+
+    /// Report all recorded allocations which are still live, grouped by call site.
+    #[cfg(feature = "leak_tracker")]
+    #[cfg_attr(doc, doc(cfg(feature = "leak_tracker")))]
+    pub fn leak_report() -> LeakReport {
+        leak::report(LeakCheckpoint::default())
+    }
+
+    /// Report allocations made after `checkpoint` which are still live, grouped by call site.
+    #[cfg(feature = "leak_tracker")]
+    #[cfg_attr(doc, doc(cfg(feature = "leak_tracker")))]
+    pub fn leak_report_since(checkpoint: LeakCheckpoint) -> LeakReport {
+        leak::report(checkpoint)
+    }
+
+    /// Print `CountingAllocator::leak_report()` to stderr when the process exits.
+    #[cfg(feature = "leak_tracker")]
+    #[cfg_attr(doc, doc(cfg(feature = "leak_tracker")))]
+    pub fn report_leaks_at_exit() {
+        leak::report_at_exit()
+    }
+}
+
+#[inline]
+fn on_alloc(_ptr: *mut u8, size: usize) {
+    counters::on_alloc(size);
+    if thread::is_enable() {
+        thread::on_alloc(size);
+    }
+    if histogram::is_enable() {
+        histogram::on_alloc(size);
+    }
+    if scope::is_active() {
+        scope::on_alloc(size);
+    }
+    if large::is_large(size) {
+        large::fire(size, None);
+    }
+    #[cfg(feature = "heap_profiler")]
+    if profiler::is_enable() {
+        profiler::on_alloc(_ptr, size);
+    }
+    #[cfg(feature = "leak_tracker")]
+    if leak::is_enable() {
+        leak::on_alloc(_ptr, size);
+    }
+}
+
+#[inline]
+fn on_dealloc(_ptr: *mut u8, size: usize) {
+    counters::on_dealloc(size);
+    if thread::is_enable() {
+        thread::on_dealloc(size);
+    }
+    if histogram::is_enable() {
+        histogram::on_dealloc(size);
+    }
+    if scope::is_active() {
+        scope::on_dealloc(size);
+    }
+}
+
+#[inline]
+fn on_realloc(_old_ptr: *mut u8, _new_ptr: *mut u8, old_size: usize, new_size: usize) {
+    counters::on_realloc(old_size, new_size);
+    if thread::is_enable() {
+        thread::on_realloc(old_size, new_size);
+    }
+    if histogram::is_enable() {
+        histogram::on_realloc(old_size, new_size);
+    }
+    if scope::is_active() {
+        scope::on_realloc(old_size, new_size);
+    }
+    if new_size > old_size && large::is_large(new_size) {
+        large::fire(new_size, Some(old_size));
+    }
+    #[cfg(feature = "heap_profiler")]
+    if profiler::is_enable() {
+        profiler::on_alloc(_new_ptr, new_size);
+    }
+    #[cfg(feature = "leak_tracker")]
+    if leak::is_enable() {
+        leak::on_alloc(_new_ptr, new_size);
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let enable = CountingAllocator::is_enable();
+        if enable && limit::exceeds_hard_limit(counters::approx_allocated(), layout.size(), layout)
+        {
+            return std::ptr::null_mut();
+        }
+        let start = if enable { latency::start() } else { None };
+        let ret = if self.tagging {
+            tag::alloc(&self.inner, layout, false, enable)
+        } else {
+            self.inner.alloc(layout)
+        };
+        if let Some(start) = start {
+            latency::record(AllocOp::Alloc, start);
+        }
+        if !ret.is_null() && enable {
+            on_alloc(ret, layout.size());
+        }
+        ret
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let enable = CountingAllocator::is_enable();
+        // samples and records must be removed before the block is freed, see `profiler::take`.
+        #[cfg(feature = "heap_profiler")]
+        if enable && profiler::has_live() {
+            profiler::take(ptr);
+        }
+        #[cfg(feature = "leak_tracker")]
+        if enable && leak::has_live() {
+            leak::take(ptr);
+        }
+        let start = if enable { latency::start() } else { None };
+        if self.tagging {
+            tag::dealloc(&self.inner, ptr, layout, enable);
+        } else {
+            self.inner.dealloc(ptr, layout);
+        }
+        if let Some(start) = start {
+            latency::record(AllocOp::Dealloc, start);
+        }
+        if enable {
+            on_dealloc(ptr, layout.size());
+        }
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let enable = CountingAllocator::is_enable();
+        if enable
+            && new_size > layout.size()
+            && limit::exceeds_hard_limit(
+                counters::approx_allocated(),
+                new_size - layout.size(),
+                Layout::from_size_align_unchecked(new_size, layout.align()),
+            )
+        {
+            return std::ptr::null_mut();
+        }
+        let count = enable && layout.align() <= MIN_ALIGN && layout.align() <= new_size;
+        #[cfg(feature = "heap_profiler")]
+        let sample = if enable && profiler::has_live() {
+            profiler::take(ptr)
+        } else {
+            None
+        };
+        #[cfg(feature = "leak_tracker")]
+        let leak_record = if enable && leak::has_live() {
+            leak::take(ptr)
+        } else {
+            None
+        };
+        let start = if enable { latency::start() } else { None };
+        let ret: *mut u8 = if self.tagging {
+            tag::realloc(&self.inner, ptr, layout, new_size, count)
+        } else {
+            self.inner.realloc(ptr, layout, new_size)
+        };
+        if let Some(start) = start {
+            latency::record(AllocOp::Realloc, start);
+        }
+        // the old block is still live if the reallocation fails,
+        // and the new one is sampled and recorded by `on_realloc` if it's counted.
+        #[cfg(feature = "heap_profiler")]
+        if let Some(sample) = sample {
+            if ret.is_null() {
+                profiler::restore(ptr, sample);
+            } else if !count {
+                profiler::restore(ret, sample);
+            }
+        }
+        #[cfg(feature = "leak_tracker")]
+        if let Some(record) = leak_record {
+            if ret.is_null() {
+                leak::restore(ptr, record);
+            } else if !count {
+                leak::restore(ret, record);
+            }
+        }
+        if !ret.is_null() && count {
+            on_realloc(ptr, ret, layout.size(), new_size);
+        }
+        ret
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let enable = CountingAllocator::is_enable();
+        if enable && limit::exceeds_hard_limit(counters::approx_allocated(), layout.size(), layout)
+        {
+            return std::ptr::null_mut();
+        }
+        let start = if enable { latency::start() } else { None };
+        let ret = if self.tagging {
+            tag::alloc(&self.inner, layout, true, enable)
+        } else {
+            self.inner.alloc_zeroed(layout)
+        };
+        if let Some(start) = start {
+            latency::record(AllocOp::Alloc, start);
+        }
+        if !ret.is_null() && enable {
+            on_alloc(ret, layout.size());
+        }
+        ret
+    }
+}
+#[cfg(feature = "allocation_counter")]
+#[global_allocator]
+static _COUNTER: CountingAllocator = CountingAllocator::new(System);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    static ALLOCATOR: CountingAllocator = CountingAllocator::new(System);
+    // the way it was declared before the allocator was generic.
+    static _COMPAT: CountingAllocator = CountingAllocator;
+
+    // The heap profiler and the leak tracker allocate on the allocating thread, which disturbs
+    // exact per-thread counters, and a huge allocation disturbs the peak, so tests doing so
+    // don't run with those checking such counters.
+    static SERIAL: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn serial() -> std::sync::MutexGuard<'static, ()> {
+        SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[test]
+    fn test_thread_counters() {
+        let _serial = serial();
+        CountingAllocator::enable();
+        CountingAllocator::enable_thread_counters();
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+
+        let handle = std::thread::spawn(move || {
+            let before = CountingAllocator::current_thread_stats().unwrap();
+            let ptr = unsafe { ALLOCATOR.alloc(layout) };
+            let after_alloc = CountingAllocator::current_thread_stats().unwrap();
+            assert_eq!(after_alloc.allocated_bytes - before.allocated_bytes, 1024);
+            assert_eq!(after_alloc.allocations - before.allocations, 1);
+
+            unsafe { ALLOCATOR.dealloc(ptr, layout) };
+            let after_dealloc = CountingAllocator::current_thread_stats().unwrap();
+            assert_eq!(after_dealloc.freed_bytes - before.freed_bytes, 1024);
+
+            assert!(CountingAllocator::all_threads_stats()
+                .iter()
+                .any(|stats| stats.tid == after_alloc.tid));
+            after_dealloc
+        });
+        let stats = handle.join().unwrap();
+
+        assert!(!CountingAllocator::all_threads_stats()
+            .iter()
+            .any(|s| s.tid == stats.tid));
+        let exited = CountingAllocator::exited_threads_stats();
+        assert!(exited.allocated_bytes >= stats.allocated_bytes);
+        assert!(exited.freed_bytes >= stats.freed_bytes);
+    }
+
+    #[test]
+    fn test_stats_and_peak() {
+        let _serial = serial();
+        CountingAllocator::enable();
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let before = CountingAllocator::stats();
+
+        let ptr = unsafe { ALLOCATOR.alloc(layout) };
+        let ptr = unsafe { ALLOCATOR.realloc(ptr, layout, 8192) };
+        unsafe { ALLOCATOR.dealloc(ptr, Layout::from_size_align(8192, 8).unwrap()) };
+        let after = CountingAllocator::stats();
+
+        assert!(after.allocations > before.allocations);
+        assert!(after.reallocations > before.reallocations);
+        assert!(after.deallocations > before.deallocations);
+        assert!(after.total_allocated >= before.total_allocated + 4096 + 8192);
+
+        // with the global allocator, other tests allocate and free large buffers concurrently.
+        if cfg!(feature = "allocation_counter") {
+            return;
+        }
+        const MIB: isize = 1 << 20;
+        // a spike far beyond the error bound of the peak, which is freed before reading the peak.
+        let layout = Layout::from_size_align(8 * MIB as usize, 8).unwrap();
+        let before = CountingAllocator::stats();
+        unsafe { ALLOCATOR.dealloc(ALLOCATOR.alloc(layout), layout) };
+        let after = CountingAllocator::stats();
+        assert!(after.allocated < before.allocated + MIB);
+        assert!(after.peak_allocated >= before.allocated + 8 * MIB - MIB);
+
+        let old_peak = CountingAllocator::reset_peak();
+        assert!(old_peak >= before.allocated + 8 * MIB - MIB);
+        let peak = CountingAllocator::stats().peak_allocated;
+        assert!((peak - CountingAllocator::get_allocated()).abs() <= MIB);
+    }
+
+    #[test]
+    fn test_wrap_allocator() {
+        struct CallCounter(AtomicUsize);
+
+        unsafe impl GlobalAlloc for CallCounter {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                System.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        static WRAPPED: CountingAllocator<CallCounter> =
+            CountingAllocator::new(CallCounter(AtomicUsize::new(0)));
+
+        CountingAllocator::enable();
+        let before = CountingAllocator::stats();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe { WRAPPED.dealloc(WRAPPED.alloc(layout), layout) };
+
+        assert_eq!(WRAPPED.inner.0.load(Ordering::SeqCst), 2);
+        let after = CountingAllocator::stats();
+        assert!(after.allocations > before.allocations);
+        assert!(after.deallocations > before.deallocations);
+    }
+
+    #[test]
+    fn test_size_histogram() {
+        CountingAllocator::enable();
+        CountingAllocator::enable_histogram();
+        // a size that other tests never allocate.
+        let layout = Layout::from_size_align(3 << 20, 8).unwrap();
+        let bucket = |buckets: Vec<SizeBucket>| {
+            buckets
+                .into_iter()
+                .find(|b| b.min_size <= layout.size() && layout.size() <= b.max_size)
+                .unwrap_or_default()
+        };
+
+        let before = bucket(CountingAllocator::size_histogram());
+        let ptr = unsafe { ALLOCATOR.alloc(layout) };
+        let after_alloc = bucket(CountingAllocator::size_histogram());
+        unsafe { ALLOCATOR.dealloc(ptr, layout) };
+        let after_dealloc = bucket(CountingAllocator::size_histogram());
+
+        assert_eq!(after_alloc.min_size, 2 << 20);
+        assert_eq!(after_alloc.max_size, (4 << 20) - 1);
+        assert_eq!(after_alloc.allocations, before.allocations + 1);
+        assert_eq!(after_alloc.live, before.live + 1);
+        assert_eq!(after_dealloc.live, before.live);
+    }
+
+    #[test]
+    fn test_latency_sampling() {
+        CountingAllocator::enable();
+        CountingAllocator::enable_latency_sampling(1);
+        assert!(CountingAllocator::is_latency_sampling_enable());
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let before = AllocOp::ALL.map(CountingAllocator::latency_histogram);
+        unsafe {
+            let ptr = ALLOCATOR.alloc(layout);
+            let ptr = ALLOCATOR.realloc(ptr, layout, 200);
+            ALLOCATOR.dealloc(ptr, Layout::from_size_align(200, 8).unwrap());
+        }
+        let after = AllocOp::ALL.map(CountingAllocator::latency_histogram);
+        CountingAllocator::disable_latency_sampling();
+
+        for (before, after) in before.iter().zip(&after) {
+            assert!(after.samples > before.samples);
+            assert!(after.percentile(50.0).unwrap() <= after.percentile(100.0).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_alloc_scope() {
+        let _serial = serial();
+        CountingAllocator::enable();
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+
+        let outer = AllocScope::new();
+        let a = unsafe { ALLOCATOR.alloc(layout) };
+        let ((), inner) = measure_alloc(|| {
+            let b = unsafe { ALLOCATOR.alloc(layout) };
+            let c = unsafe { ALLOCATOR.alloc(layout) };
+            unsafe { ALLOCATOR.dealloc(b, layout) };
+            unsafe { ALLOCATOR.dealloc(c, layout) };
+        });
+        unsafe { ALLOCATOR.dealloc(a, layout) };
+        let outer = outer.stats();
+
+        assert_eq!(
+            inner,
+            ScopeStats {
+                allocated_bytes: 2000,
+                freed_bytes: 2000,
+                allocations: 2,
+                peak_bytes: 2000,
+            }
+        );
+        assert_eq!(
+            outer,
+            ScopeStats {
+                allocated_bytes: 3000,
+                freed_bytes: 3000,
+                allocations: 3,
+                peak_bytes: 3000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_large_alloc_hook() {
+        static LARGE: AtomicUsize = AtomicUsize::new(0);
+        let _serial = serial();
+        CountingAllocator::enable();
+        let threshold = 256 << 20;
+        CountingAllocator::set_large_alloc_hook(threshold, |alloc| {
+            // allocations made by the hook don't fire it again.
+            let _ = vec![0u8; alloc.size];
+            LARGE.fetch_add(1, Ordering::SeqCst);
+            #[cfg(feature = "backtrace")]
+            assert!(alloc
+                .backtrace()
+                .iter()
+                .any(|symbol| symbol.function.contains("test_large_alloc_hook")));
+        });
+
+        let layout = Layout::from_size_align(threshold / 2, 8).unwrap();
+        let ptr = unsafe { ALLOCATOR.alloc(layout) };
+        assert_eq!(LARGE.load(Ordering::SeqCst), 0);
+        let ptr = unsafe { ALLOCATOR.realloc(ptr, layout, threshold) };
+        assert_eq!(LARGE.load(Ordering::SeqCst), 1);
+        CountingAllocator::clear_large_alloc_hook();
+        unsafe { ALLOCATOR.dealloc(ptr, Layout::from_size_align(threshold, 8).unwrap()) };
+    }
+
+    #[test]
+    fn test_tag_stats() {
+        static TAGGED: CountingAllocator = CountingAllocator::new(System).with_tagging();
+        const CACHE: Tag = Tag::new(35);
+        CountingAllocator::enable();
+        let layout = Layout::from_size_align(1000, 64).unwrap();
+        let small = Layout::from_size_align(1000, 8).unwrap();
+
+        let (a, b) = with_tag(CACHE, || unsafe {
+            assert_eq!(current_tag(), CACHE);
+            let a = TAGGED.alloc(layout);
+            let b = TAGGED.alloc_zeroed(small);
+            assert_eq!(a as usize % 64, 0);
+            (a, TAGGED.realloc(b, small, 3000))
+        });
+        assert_eq!(current_tag(), Tag::UNTAGGED);
+        assert_eq!(
+            CountingAllocator::tag_stats(CACHE),
+            TagStats {
+                allocated: 4000,
+                total_allocated: 5000,
+                allocations: 2,
+            }
+        );
+
+        // frees are credited to the tag which made the allocation.
+        unsafe {
+            TAGGED.dealloc(a, layout);
+            TAGGED.dealloc(b, Layout::from_size_align(3000, 8).unwrap());
+        }
+        assert_eq!(CountingAllocator::tag_stats(CACHE).allocated, 0);
+        assert!(CountingAllocator::all_tag_stats()
+            .iter()
+            .any(|(tag, _)| *tag == CACHE));
+    }
+
+    #[cfg(feature = "heap_profiler")]
+    #[test]
+    fn test_heap_profile() {
+        let _serial = serial();
+        CountingAllocator::enable();
+        CountingAllocator::start_heap_profiling(1);
+        let layout = Layout::from_size_align(1 << 20, 8).unwrap();
+        let ptr = unsafe { ALLOCATOR.alloc(layout) };
+
+        let mut profile = vec![];
+        CountingAllocator::write_heap_profile(&mut profile).unwrap();
+        unsafe { ALLOCATOR.dealloc(ptr, layout) };
+        CountingAllocator::stop_heap_profiling();
+
+        // every allocation is sampled with interval 1, so the weight is 1 and
+        // `value: [1, 1048576]` is encoded as a packed field in a sample.
+        let value = [0x12, 0x04, 0x01, 0x80, 0x80, 0x40];
+        assert!(profile.windows(value.len()).any(|w| w == value));
+    }
+
+    #[cfg(all(feature = "heap_profiler", feature = "leak_tracker"))]
+    #[test]
+    fn test_heap_profile_with_leak_tracking() {
+        let _serial = serial();
+        CountingAllocator::enable();
+        CountingAllocator::start_heap_profiling(1);
+        CountingAllocator::start_leak_tracking();
+        // the records of both grow concurrently, which allocate inside their locks.
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let ptrs = (1..2000)
+                        .map(|size| {
+                            let layout = Layout::from_size_align(size, 8).unwrap();
+                            (unsafe { ALLOCATOR.alloc(layout) }, layout)
+                        })
+                        .collect::<Vec<_>>();
+                    for (ptr, layout) in ptrs {
+                        unsafe { ALLOCATOR.dealloc(ptr, layout) };
+                    }
+                });
+            }
+        });
+        CountingAllocator::stop_leak_tracking();
+        CountingAllocator::stop_heap_profiling();
+    }
+
+    #[cfg(feature = "leak_tracker")]
+    #[test]
+    fn test_leak_report() {
+        let _serial = serial();
+        #[inline(never)]
+        fn leak_here(layout: Layout) -> *mut u8 {
+            // not a tail call, so that the frame is kept in release builds.
+            std::hint::black_box(unsafe { ALLOCATOR.alloc(layout) })
+        }
+
+        CountingAllocator::enable();
+        CountingAllocator::start_leak_tracking();
+        let checkpoint = CountingAllocator::leak_checkpoint();
+        // a size that other tests never allocate.
+        let layout = Layout::from_size_align(12345, 8).unwrap();
+        let ptrs = (0..3).map(|_| leak_here(layout)).collect::<Vec<_>>();
+
+        let report = CountingAllocator::leak_report_since(checkpoint);
+        let site = report
+            .sites
+            .iter()
+            .find(|site| site.bytes == 3 * 12345)
+            .unwrap();
+        assert_eq!(site.count, 3);
+        assert!(site.backtrace[0].function.ends_with("leak_here"));
+
+        for ptr in ptrs {
+            unsafe { ALLOCATOR.dealloc(ptr, layout) };
+        }
+        let report = CountingAllocator::leak_report_since(checkpoint);
+        assert!(!report.sites.iter().any(|site| site.bytes == 3 * 12345));
+        CountingAllocator::stop_leak_tracking();
+    }
+}
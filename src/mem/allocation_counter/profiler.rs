@@ -0,0 +1,312 @@
+//! A sampled heap profiler.
+//!
+//! An allocation is sampled about every `sample_interval` bytes. Intervals between samples follow
+//! an exponential distribution (a Poisson process, like tcmalloc and jemalloc), so allocations of
+//! any size have a chance to be sampled and the sampled bytes can be scaled to an unbiased estimate.
+//!
+//! Sampled allocations are kept in a lock-free table of addresses until they are freed, so that
+//! the deallocation path doesn't lock unless the address is sampled. Allocations made by the
+//! profiler itself are never sampled, which keeps the sampler from recursing into the allocator.
+
+use super::{
+    bookkeeping,
+    frames::{self, Frames},
+    in_bookkeeping,
+};
+use crate::utils::pprof::{Line, ProfileBuilder};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const TABLE_BITS: u32 = 16;
+const TABLE_SIZE: usize = 1 << TABLE_BITS;
+const MAX_PROBE: usize = 64;
+const EMPTY: usize = 0;
+const TOMBSTONE: usize = 1;
+
+/// Frames of the allocator itself and its callees are dropped from the profile.
+const DROP_FRAMES: &str = ".*(__rust_alloc|__rust_alloc_zeroed|__rust_realloc|__rg_alloc|__rg_alloc_zeroed|__rg_realloc).*|<?perf_monitor::mem::allocation_counter::.*";
+
+/// A sampled allocation, returned by `take` so that it can be restored if the allocation is not freed.
+pub(super) struct Sample {
+    size: usize,
+    frames: Frames,
+}
+
+static ENABLE: AtomicBool = AtomicBool::new(false);
+static SAMPLE_INTERVAL: AtomicUsize = AtomicUsize::new(512 * 1024);
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_ENTRY: AtomicUsize = AtomicUsize::new(EMPTY);
+static TABLE: [AtomicUsize; TABLE_SIZE] = [EMPTY_ENTRY; TABLE_SIZE];
+static SAMPLES: Mutex<Option<HashMap<usize, Sample>>> = Mutex::new(None);
+
+struct Local {
+    bytes_until_sample: Cell<i64>,
+    rng: Cell<u64>,
+}
+
+thread_local! {
+    static LOCAL: Local = const {
+        Local {
+            bytes_until_sample: Cell::new(0),
+            rng: Cell::new(0),
+        }
+    };
+}
+
+impl Local {
+    /// xorshift64*, seeded by the address of the thread local.
+    fn next_u64(&self) -> u64 {
+        let mut x = self.rng.get();
+        if x == 0 {
+            x = (self as *const Local as u64) ^ 0x9E37_79B9_7F4A_7C15;
+        }
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.rng.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Draw the bytes until next sample from an exponential distribution.
+    fn next_interval(&self, mean: usize) -> i64 {
+        // uniform in (0, 1]
+        let u = ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        (-u.ln() * mean as f64).max(1.0) as i64
+    }
+}
+
+#[inline]
+fn probe(ptr: usize) -> impl Iterator<Item = &'static AtomicUsize> {
+    let hash = ((ptr >> 4) as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - TABLE_BITS);
+    (0..MAX_PROBE).map(move |i| &TABLE[(hash as usize + i) % TABLE_SIZE])
+}
+
+fn table_insert(ptr: usize) -> bool {
+    for entry in probe(ptr) {
+        let cur = entry.load(Ordering::Relaxed);
+        if (cur == EMPTY || cur == TOMBSTONE)
+            && entry
+                .compare_exchange(cur, ptr, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        {
+            LIVE.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+    }
+    false
+}
+
+fn table_remove(ptr: usize) -> bool {
+    for entry in probe(ptr) {
+        match entry.load(Ordering::Acquire) {
+            EMPTY => return false,
+            cur if cur == ptr => {
+                if entry
+                    .compare_exchange(cur, TOMBSTONE, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    LIVE.fetch_sub(1, Ordering::Relaxed);
+                    return true;
+                }
+                return false;
+            }
+            _ => continue,
+        }
+    }
+    false
+}
+
+fn table_contains(ptr: usize) -> bool {
+    for entry in probe(ptr) {
+        match entry.load(Ordering::Acquire) {
+            EMPTY => return false,
+            cur if cur == ptr => return true,
+            _ => continue,
+        }
+    }
+    false
+}
+
+#[inline]
+pub(super) fn is_enable() -> bool {
+    ENABLE.load(Ordering::Relaxed)
+}
+
+/// Whether there are live samples to be removed on deallocation.
+#[inline]
+pub(super) fn has_live() -> bool {
+    LIVE.load(Ordering::Relaxed) > 0
+}
+
+pub(super) fn start(sample_interval: usize) {
+    SAMPLE_INTERVAL.store(sample_interval.max(1), Ordering::SeqCst);
+    ENABLE.store(true, Ordering::SeqCst);
+}
+
+pub(super) fn stop() {
+    ENABLE.store(false, Ordering::SeqCst);
+    bookkeeping(|| {
+        for entry in TABLE.iter() {
+            if entry.swap(EMPTY, Ordering::AcqRel) > TOMBSTONE {
+                LIVE.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        *SAMPLES.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    });
+}
+
+#[inline]
+pub(super) fn on_alloc(ptr: *mut u8, size: usize) {
+    if in_bookkeeping() {
+        return;
+    }
+    let _ = LOCAL.try_with(|local| {
+        let sample_interval = SAMPLE_INTERVAL.load(Ordering::Relaxed);
+        let mut left = local.bytes_until_sample.get();
+        if local.rng.get() == 0 {
+            left = local.next_interval(sample_interval);
+        }
+        left -= size as i64;
+        if left > 0 {
+            local.bytes_until_sample.set(left);
+            return;
+        }
+        local
+            .bytes_until_sample
+            .set(local.next_interval(sample_interval));
+        bookkeeping(|| record(ptr as usize, size));
+    });
+}
+
+#[cold]
+fn record(ptr: usize, size: usize) {
+    let frames = Frames::capture();
+    insert(ptr, Sample { size, frames });
+}
+
+fn insert(ptr: usize, sample: Sample) {
+    if !table_insert(ptr) {
+        return;
+    }
+    SAMPLES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(ptr, sample);
+}
+
+/// Remove the sample of `ptr`, which must be called before `ptr` is freed,
+/// otherwise another thread may get the same address and its sample would be removed.
+#[inline]
+pub(super) fn take(ptr: *mut u8) -> Option<Sample> {
+    if !table_remove(ptr as usize) {
+        return None;
+    }
+    // A deallocation made inside `bookkeeping`, e.g. while holding the lock, must not lock again,
+    // the stale sample is ignored since it's no longer in the table.
+    bookkeeping(|| {
+        SAMPLES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_mut()
+            .and_then(|samples| samples.remove(&(ptr as usize)))
+    })
+    .flatten()
+}
+
+/// Put back a sample taken by `take`, e.g. when a reallocation fails.
+#[cold]
+pub(super) fn restore(ptr: *mut u8, sample: Sample) {
+    bookkeeping(|| insert(ptr as usize, sample));
+}
+
+pub(super) fn write_heap_profile(w: &mut dyn Write) -> io::Result<()> {
+    bookkeeping(|| write_heap_profile_impl(w)).unwrap_or_else(|| {
+        Err(io::Error::other(
+            "heap profile can't be written inside the allocator",
+        ))
+    })
+}
+
+fn write_heap_profile_impl(w: &mut dyn Write) -> io::Result<()> {
+    let sample_interval = SAMPLE_INTERVAL.load(Ordering::SeqCst);
+    let samples = SAMPLES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .flatten()
+        .filter(|(&ptr, _)| table_contains(ptr))
+        .map(|(_, sample)| (sample.size, sample.frames))
+        .collect::<Vec<_>>();
+
+    let mut builder = ProfileBuilder::new(
+        &[("inuse_objects", "count"), ("inuse_space", "bytes")],
+        ("space", "bytes"),
+        sample_interval as i64,
+    );
+    builder.drop_frames(DROP_FRAMES);
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        builder.time_nanos(now.as_nanos() as i64);
+    }
+    for (size, frames) in samples {
+        // an allocation of `size` bytes is sampled with probability `1 - exp(-size / interval)`.
+        let probability = 1.0 - (-(size as f64) / sample_interval as f64).exp();
+        let weight = 1.0 / probability;
+        let location_ids = frames
+            .ips()
+            .iter()
+            .map(|&ip| {
+                builder.location(ip as u64, || {
+                    frames::resolve(ip)
+                        .into_iter()
+                        .map(|symbol| Line {
+                            function: symbol.function,
+                            filename: symbol.filename,
+                            line: symbol.line as i64,
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        builder.sample(
+            location_ids,
+            vec![weight.round() as i64, (weight * size as f64).round() as i64],
+        );
+    }
+    w.write_all(&builder.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let ptr = 0xdead_bee0;
+        assert!(!table_contains(ptr));
+        assert!(table_insert(ptr));
+        assert!(table_contains(ptr));
+        assert!(table_remove(ptr));
+        assert!(!table_contains(ptr));
+        assert!(!table_remove(ptr));
+    }
+
+    #[test]
+    fn test_next_interval() {
+        LOCAL.with(|local| {
+            let n = 10_000;
+            let sum: i64 = (0..n).map(|_| local.next_interval(4096)).sum();
+            let mean = sum as f64 / n as f64;
+            assert!((mean - 4096.0).abs() < 4096.0 * 0.1);
+        });
+    }
+}
@@ -0,0 +1,163 @@
+//! Scoped allocation measurement of current thread.
+
+use std::{
+    cell::Cell,
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Counters of current thread, updated only when there are active scopes on this thread.
+struct LocalCounters {
+    depth: Cell<usize>,
+    allocated_bytes: Cell<u64>,
+    freed_bytes: Cell<u64>,
+    allocations: Cell<u64>,
+    inuse: Cell<i64>,
+    peak: Cell<i64>,
+}
+
+thread_local! {
+    static LOCAL: LocalCounters = const {
+        LocalCounters {
+            depth: Cell::new(0),
+            allocated_bytes: Cell::new(0),
+            freed_bytes: Cell::new(0),
+            allocations: Cell::new(0),
+            inuse: Cell::new(0),
+            peak: Cell::new(0),
+        }
+    };
+}
+
+/// The number of active scopes of all threads, which makes the allocator skip the thread local
+/// when no scope is active.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+pub(super) fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed) > 0
+}
+
+#[inline]
+fn update(allocated: usize, freed: usize, allocations: u64) {
+    let _ = LOCAL.try_with(|local| {
+        if local.depth.get() == 0 {
+            return;
+        }
+        local
+            .allocated_bytes
+            .set(local.allocated_bytes.get() + allocated as u64);
+        local
+            .freed_bytes
+            .set(local.freed_bytes.get() + freed as u64);
+        local.allocations.set(local.allocations.get() + allocations);
+        let inuse = local.inuse.get() + allocated as i64 - freed as i64;
+        local.inuse.set(inuse);
+        if inuse > local.peak.get() {
+            local.peak.set(inuse);
+        }
+    });
+}
+
+#[inline]
+pub(super) fn on_alloc(size: usize) {
+    update(size, 0, 1)
+}
+
+#[inline]
+pub(super) fn on_dealloc(size: usize) {
+    update(0, size, 0)
+}
+
+#[inline]
+pub(super) fn on_realloc(old_size: usize, new_size: usize) {
+    update(new_size, old_size, 1)
+}
+
+/// Allocation counters of a scope returned by `AllocScope::stats` and `measure_alloc`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScopeStats {
+    /// bytes allocated in the scope, including the new size of reallocations.
+    pub allocated_bytes: u64,
+    /// bytes freed in the scope, including the old size of reallocations.
+    pub freed_bytes: u64,
+    /// the number of allocations and reallocations in the scope.
+    pub allocations: u64,
+    /// the peak inuse bytes in the scope, relative to the inuse bytes when the scope begins.
+    pub peak_bytes: u64,
+}
+
+/// A RAII guard that measures the allocations of current thread since it's created.
+///
+/// Scopes can be nested, an outer scope also counts the allocations of its inner scopes.
+/// Allocations are counted only when `CountingAllocator` is the global allocator and the counter is enable.
+///
+/// ```ignore
+/// let scope = AllocScope::new();
+/// handle_request();
+/// println!("{:?}", scope.stats());
+/// ```
+pub struct AllocScope {
+    allocated_bytes: u64,
+    freed_bytes: u64,
+    allocations: u64,
+    inuse: i64,
+    outer_peak: i64,
+    _mark: PhantomData<*const ()>, // make it !Sync & !Send
+}
+
+impl AllocScope {
+    /// Begin a scope on current thread.
+    pub fn new() -> Self {
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        LOCAL.with(|local| {
+            local.depth.set(local.depth.get() + 1);
+            let inuse = local.inuse.get();
+            AllocScope {
+                allocated_bytes: local.allocated_bytes.get(),
+                freed_bytes: local.freed_bytes.get(),
+                allocations: local.allocations.get(),
+                inuse,
+                outer_peak: local.peak.replace(inuse),
+                _mark: PhantomData,
+            }
+        })
+    }
+
+    /// Get the counters since this scope begins.
+    pub fn stats(&self) -> ScopeStats {
+        LOCAL.with(|local| ScopeStats {
+            allocated_bytes: local.allocated_bytes.get() - self.allocated_bytes,
+            freed_bytes: local.freed_bytes.get() - self.freed_bytes,
+            allocations: local.allocations.get() - self.allocations,
+            peak_bytes: (local.peak.get() - self.inuse).max(0) as u64,
+        })
+    }
+}
+
+impl Default for AllocScope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for AllocScope {
+    fn drop(&mut self) {
+        let _ = LOCAL.try_with(|local| {
+            local.depth.set(local.depth.get() - 1);
+            if self.outer_peak > local.peak.get() {
+                local.peak.set(self.outer_peak);
+            }
+        });
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Run `f` on current thread and return its result with the allocations it performs.
+///
+/// See `AllocScope` for details.
+pub fn measure_alloc<R>(f: impl FnOnce() -> R) -> (R, ScopeStats) {
+    let scope = AllocScope::new();
+    let ret = f();
+    (ret, scope.stats())
+}
@@ -0,0 +1,221 @@
+//! Allocation tagging by subsystem.
+//!
+//! A tagging allocator prepends a header to every block, which records the tag of current thread
+//! when the block is allocated. So a block is credited to the tag which allocates it,
+//! no matter which thread or tag frees it.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    sync::atomic::{AtomicIsize, AtomicU64, Ordering},
+};
+
+/// The max number of tags.
+pub const MAX_TAGS: usize = u8::MAX as usize + 1;
+
+/// A category of allocations defined by users, e.g. cache, network buffers and rendering.
+///
+/// ```ignore
+/// const CACHE: Tag = Tag::new(1);
+/// let cache = with_tag(CACHE, || build_cache());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tag(u8);
+
+impl Tag {
+    /// Allocations made out of any tag scope.
+    pub const UNTAGGED: Tag = Tag(0);
+
+    pub const fn new(id: u8) -> Self {
+        Tag(id)
+    }
+
+    pub const fn id(self) -> u8 {
+        self.0
+    }
+}
+
+/// Counters of a tag returned by `CountingAllocator::tag_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TagStats {
+    /// the inuse bytes allocated under this tag.
+    pub allocated: isize,
+    /// bytes ever allocated under this tag (cumulative), including the new size of reallocations.
+    pub total_allocated: u64,
+    /// the number of allocations under this tag (cumulative).
+    pub allocations: u64,
+}
+
+struct Counters {
+    allocated: AtomicIsize,
+    total_allocated: AtomicU64,
+    allocations: AtomicU64,
+}
+
+impl Counters {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Counters = Counters {
+        allocated: AtomicIsize::new(0),
+        total_allocated: AtomicU64::new(0),
+        allocations: AtomicU64::new(0),
+    };
+}
+
+static COUNTERS: [Counters; MAX_TAGS] = [Counters::EMPTY; MAX_TAGS];
+
+thread_local! {
+    static CURRENT: Cell<Tag> = const { Cell::new(Tag::UNTAGGED) };
+}
+
+/// A RAII guard which sets the tag of current thread and restores the previous one when dropped.
+pub struct TagGuard {
+    prev: Tag,
+    _mark: std::marker::PhantomData<*const ()>, // make it !Sync & !Send
+}
+
+impl TagGuard {
+    pub fn new(tag: Tag) -> Self {
+        TagGuard {
+            prev: CURRENT.with(|current| current.replace(tag)),
+            _mark: std::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        let _ = CURRENT.try_with(|current| current.set(self.prev));
+    }
+}
+
+/// Run `f` with `tag` as the tag of current thread,
+/// allocations made by a tagging `CountingAllocator` in `f` are charged to `tag`.
+pub fn with_tag<R>(tag: Tag, f: impl FnOnce() -> R) -> R {
+    let _guard = TagGuard::new(tag);
+    f()
+}
+
+/// Get the tag of current thread.
+pub fn current_tag() -> Tag {
+    CURRENT.try_with(Cell::get).unwrap_or_default()
+}
+
+pub(super) fn stats(tag: Tag) -> TagStats {
+    let counters = &COUNTERS[tag.0 as usize];
+    TagStats {
+        allocated: counters.allocated.load(Ordering::Relaxed),
+        total_allocated: counters.total_allocated.load(Ordering::Relaxed),
+        allocations: counters.allocations.load(Ordering::Relaxed),
+    }
+}
+
+pub(super) fn all_stats() -> Vec<(Tag, TagStats)> {
+    (0..MAX_TAGS)
+        .map(|id| Tag(id as u8))
+        .map(|tag| (tag, stats(tag)))
+        .filter(|(_, stats)| stats.allocations > 0)
+        .collect()
+}
+
+/// The header is big enough to hold the tag and keeps the block aligned.
+#[inline]
+fn header_size(layout: Layout) -> usize {
+    layout.align().max(16)
+}
+
+#[inline]
+fn outer_layout(layout: Layout, size: usize) -> Option<Layout> {
+    let size = size.checked_add(header_size(layout))?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+#[inline]
+unsafe fn header(ptr: *mut u8) -> *mut u8 {
+    ptr.sub(1)
+}
+
+#[inline]
+fn charge(tag: Tag, size: usize) {
+    let counters = &COUNTERS[tag.0 as usize];
+    counters
+        .allocated
+        .fetch_add(size as isize, Ordering::Relaxed);
+    counters
+        .total_allocated
+        .fetch_add(size as u64, Ordering::Relaxed);
+    counters.allocations.fetch_add(1, Ordering::Relaxed);
+}
+
+#[inline]
+pub(super) unsafe fn alloc<A: GlobalAlloc>(
+    inner: &A,
+    layout: Layout,
+    zeroed: bool,
+    count: bool,
+) -> *mut u8 {
+    let outer = match outer_layout(layout, layout.size()) {
+        Some(outer) => outer,
+        None => return std::ptr::null_mut(),
+    };
+    let base = if zeroed {
+        inner.alloc_zeroed(outer)
+    } else {
+        inner.alloc(outer)
+    };
+    if base.is_null() {
+        return base;
+    }
+    let ptr = base.add(header_size(layout));
+    let tag = current_tag();
+    *header(ptr) = tag.0;
+    if count {
+        charge(tag, layout.size());
+    }
+    ptr
+}
+
+#[inline]
+pub(super) unsafe fn dealloc<A: GlobalAlloc>(inner: &A, ptr: *mut u8, layout: Layout, count: bool) {
+    let tag = Tag(*header(ptr));
+    if count {
+        COUNTERS[tag.0 as usize]
+            .allocated
+            .fetch_sub(layout.size() as isize, Ordering::Relaxed);
+    }
+    let outer =
+        Layout::from_size_align_unchecked(layout.size() + header_size(layout), layout.align());
+    inner.dealloc(ptr.sub(header_size(layout)), outer);
+}
+
+#[inline]
+pub(super) unsafe fn realloc<A: GlobalAlloc>(
+    inner: &A,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+    count: bool,
+) -> *mut u8 {
+    let new_outer = match outer_layout(layout, new_size) {
+        Some(new_outer) => new_outer,
+        None => return std::ptr::null_mut(),
+    };
+    let outer =
+        Layout::from_size_align_unchecked(layout.size() + header_size(layout), layout.align());
+    // the header is moved with the block, so the block is still credited to its tag.
+    let base = inner.realloc(ptr.sub(header_size(layout)), outer, new_outer.size());
+    if base.is_null() {
+        return base;
+    }
+    let ptr = base.add(header_size(layout));
+    if count {
+        let counters = &COUNTERS[*header(ptr) as usize];
+        counters.allocated.fetch_add(
+            new_size as isize - layout.size() as isize,
+            Ordering::Relaxed,
+        );
+        counters
+            .total_allocated
+            .fetch_add(new_size as u64, Ordering::Relaxed);
+    }
+    ptr
+}
@@ -0,0 +1,219 @@
+//! Per-thread allocation counters.
+//!
+//! Every thread claims a slot of a static registry on its first tracked allocation,
+//! so that counters of all live threads can be read from any thread without allocating.
+//! When a thread exits, its counters are flushed to the exited total and the slot is released.
+//! Threads beyond `MAX_TRACKED_THREADS` are only counted by the global counter.
+
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+/// The max number of live threads whose counters can be tracked at the same time.
+pub const MAX_TRACKED_THREADS: usize = 1024;
+
+const UNCLAIMED: usize = usize::MAX;
+const NO_SLOT: usize = usize::MAX - 1;
+
+/// Allocation counters of a thread returned by
+/// `CountingAllocator::current_thread_stats` and `CountingAllocator::all_threads_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThreadAllocStats {
+    /// The kernel thread id, which is **NOT** `std::thread::ThreadId`.
+    /// It is 0 for `CountingAllocator::exited_threads_stats`.
+    pub tid: u64,
+    /// bytes allocated by this thread (cumulative), including the new size of reallocations.
+    pub allocated_bytes: u64,
+    /// bytes freed by this thread (cumulative), including the old size of reallocations.
+    pub freed_bytes: u64,
+    /// the number of allocations and reallocations performed by this thread (cumulative).
+    pub allocations: u64,
+}
+
+#[repr(align(64))]
+struct Slot {
+    in_use: AtomicBool,
+    tid: AtomicU64,
+    allocated_bytes: AtomicU64,
+    freed_bytes: AtomicU64,
+    allocations: AtomicU64,
+}
+
+impl Slot {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Slot = Slot {
+        in_use: AtomicBool::new(false),
+        tid: AtomicU64::new(0),
+        allocated_bytes: AtomicU64::new(0),
+        freed_bytes: AtomicU64::new(0),
+        allocations: AtomicU64::new(0),
+    };
+
+    fn stats(&self) -> ThreadAllocStats {
+        ThreadAllocStats {
+            tid: self.tid.load(Ordering::Relaxed),
+            allocated_bytes: self.allocated_bytes.load(Ordering::Relaxed),
+            freed_bytes: self.freed_bytes.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static SLOTS: [Slot; MAX_TRACKED_THREADS] = [Slot::EMPTY; MAX_TRACKED_THREADS];
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+static EXITED: Slot = Slot::EMPTY;
+static ENABLE: AtomicBool = AtomicBool::new(false);
+
+/// Owns the registry slot of current thread and releases it when the thread exits.
+struct LocalSlot(Cell<usize>);
+
+impl Drop for LocalSlot {
+    fn drop(&mut self) {
+        let index = self.0.get();
+        if index >= MAX_TRACKED_THREADS {
+            return;
+        }
+        let slot = &SLOTS[index];
+        let stats = slot.stats();
+        EXITED
+            .allocated_bytes
+            .fetch_add(stats.allocated_bytes, Ordering::Relaxed);
+        EXITED
+            .freed_bytes
+            .fetch_add(stats.freed_bytes, Ordering::Relaxed);
+        EXITED
+            .allocations
+            .fetch_add(stats.allocations, Ordering::Relaxed);
+        slot.allocated_bytes.store(0, Ordering::Relaxed);
+        slot.freed_bytes.store(0, Ordering::Relaxed);
+        slot.allocations.store(0, Ordering::Relaxed);
+        slot.tid.store(0, Ordering::Relaxed);
+        slot.in_use.store(false, Ordering::Release);
+    }
+}
+
+thread_local! {
+    // Registering the destructor of `LOCAL_SLOT` may allocate on some platforms,
+    // `IN_TRACKING` is destructor-free and prevents the allocator from recursing.
+    static IN_TRACKING: Cell<bool> = const { Cell::new(false) };
+    static LOCAL_SLOT: LocalSlot = const { LocalSlot(Cell::new(UNCLAIMED)) };
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn os_thread_id() -> u64 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub(crate) fn os_thread_id() -> u64 {
+    let mut tid = 0u64;
+    unsafe { libc::pthread_threadid_np(0 as libc::pthread_t, &mut tid) };
+    tid
+}
+
+#[cfg(target_os = "windows")]
+pub(crate) fn os_thread_id() -> u64 {
+    unsafe { windows_sys::Win32::System::Threading::GetCurrentThreadId() as u64 }
+}
+
+fn claim_slot() -> usize {
+    let start = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+    for i in 0..MAX_TRACKED_THREADS {
+        let index = (start + i) % MAX_TRACKED_THREADS;
+        let slot = &SLOTS[index];
+        if slot
+            .in_use
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            slot.tid.store(os_thread_id(), Ordering::Relaxed);
+            return index;
+        }
+    }
+    NO_SLOT
+}
+
+/// Run `f` with the registry slot of current thread,
+/// `f` won't be invoked when the allocator is re-entered or the thread is exiting.
+#[inline]
+fn with_slot<R>(f: impl FnOnce(&Slot) -> R) -> Option<R> {
+    IN_TRACKING
+        .try_with(|in_tracking| {
+            if in_tracking.replace(true) {
+                return None;
+            }
+            let ret = LOCAL_SLOT
+                .try_with(|local| {
+                    let mut index = local.0.get();
+                    if index == UNCLAIMED {
+                        index = claim_slot();
+                        local.0.set(index);
+                    }
+                    SLOTS.get(index).map(f)
+                })
+                .ok()
+                .flatten();
+            in_tracking.set(false);
+            ret
+        })
+        .ok()
+        .flatten()
+}
+
+#[inline]
+pub(super) fn is_enable() -> bool {
+    ENABLE.load(Ordering::Relaxed)
+}
+
+pub(super) fn enable() {
+    ENABLE.store(true, Ordering::SeqCst)
+}
+
+pub(super) fn disable() {
+    ENABLE.store(false, Ordering::SeqCst)
+}
+
+#[inline]
+pub(super) fn on_alloc(size: usize) {
+    with_slot(|slot| {
+        slot.allocated_bytes
+            .fetch_add(size as u64, Ordering::Relaxed);
+        slot.allocations.fetch_add(1, Ordering::Relaxed);
+    });
+}
+
+#[inline]
+pub(super) fn on_dealloc(size: usize) {
+    with_slot(|slot| {
+        slot.freed_bytes.fetch_add(size as u64, Ordering::Relaxed);
+    });
+}
+
+#[inline]
+pub(super) fn on_realloc(old_size: usize, new_size: usize) {
+    with_slot(|slot| {
+        slot.allocated_bytes
+            .fetch_add(new_size as u64, Ordering::Relaxed);
+        slot.freed_bytes
+            .fetch_add(old_size as u64, Ordering::Relaxed);
+        slot.allocations.fetch_add(1, Ordering::Relaxed);
+    });
+}
+
+pub(super) fn current_thread_stats() -> Option<ThreadAllocStats> {
+    with_slot(Slot::stats)
+}
+
+pub(super) fn all_threads_stats() -> Vec<ThreadAllocStats> {
+    SLOTS
+        .iter()
+        .filter(|slot| slot.in_use.load(Ordering::Acquire))
+        .map(Slot::stats)
+        .filter(|stats| stats.tid != 0)
+        .collect()
+}
+
+pub(super) fn exited_threads_stats() -> ThreadAllocStats {
+    EXITED.stats()
+}
@@ -0,0 +1,383 @@
+//! Memory limits, usage and events of the cgroup of current process.
+//!
+//! Both cgroup v2 and v1 are supported. In the hybrid mode the memory controller
+//! is attached to v1, so v1 is preferred if the memory controller is found there.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+/// v1 reports this (or a bigger value) for no limit, i.e. `PAGE_COUNTER_MAX` pages.
+const V1_UNLIMITED: u64 = 1 << 62;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupVersion {
+    V1,
+    V2,
+}
+
+/// The memory cgroup of a process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryCgroup {
+    version: CgroupVersion,
+    path: PathBuf,
+}
+
+/// Memory usage and limits returned by `MemoryCgroup::stats`, all fields are in bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// `memory.current`, or `memory.usage_in_bytes` of v1.
+    pub usage: u64,
+    /// `memory.max`, or `memory.limit_in_bytes` of v1. `None` if unlimited.
+    pub limit: Option<u64>,
+    /// `memory.high`, or `memory.soft_limit_in_bytes` of v1. `None` if unlimited.
+    pub high: Option<u64>,
+    /// `memory.peak`, or `memory.max_usage_in_bytes` of v1. `None` if not supported by the kernel.
+    pub peak: Option<u64>,
+    /// `memory.swap.current`, or `memory.memsw.usage_in_bytes - memory.usage_in_bytes` of v1.
+    /// `None` if swap accounting is disable.
+    pub swap_usage: Option<u64>,
+}
+
+impl MemoryStats {
+    /// The ratio of usage to the limit, or `None` if unlimited.
+    ///
+    /// The OOM killer acts when it reaches 1 and nothing can be reclaimed.
+    pub fn usage_ratio(&self) -> Option<f64> {
+        self.limit.map(|limit| self.usage as f64 / limit as f64)
+    }
+}
+
+/// Cumulative counters of `memory.events`.
+///
+/// v1 has fewer events, `max` is `memory.failcnt`, `oom_kill` is read from `memory.oom_control`
+/// and others are always 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryEvents {
+    /// times the usage was under the low boundary but reclaimed.
+    pub low: u64,
+    /// times the usage exceeded `memory.high` and was throttled.
+    pub high: u64,
+    /// times the usage was about to exceed `memory.max`.
+    pub max: u64,
+    /// times the usage hit the limit and the allocation failed.
+    pub oom: u64,
+    /// times a process was killed by the OOM killer.
+    pub oom_kill: u64,
+}
+
+impl MemoryCgroup {
+    /// Find the memory cgroup of current process by `/proc/self/cgroup` and `/proc/self/mountinfo`.
+    pub fn current() -> Result<Self> {
+        let cgroup = fs::read_to_string("/proc/self/cgroup")?;
+        let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+        locate(&cgroup, &mountinfo).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                "memory cgroup of current process is not found",
+            )
+        })
+    }
+
+    /// Use the cgroup directory `path`, e.g. `/sys/fs/cgroup/user.slice`.
+    pub fn from_path(version: CgroupVersion, path: impl Into<PathBuf>) -> Self {
+        MemoryCgroup {
+            version,
+            path: path.into(),
+        }
+    }
+
+    pub fn version(&self) -> CgroupVersion {
+        self.version
+    }
+
+    /// Get the cgroup directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self, name: &str) -> Result<String> {
+        fs::read_to_string(self.path.join(name))
+    }
+
+    fn read_u64(&self, name: &str) -> Result<u64> {
+        let value = self.read(name)?;
+        value.trim().parse().map_err(|_| invalid_data(name, &value))
+    }
+
+    fn read_limit(&self, name: &str) -> Result<Option<u64>> {
+        let value = self.read(name)?;
+        match (self.version, value.trim()) {
+            (CgroupVersion::V2, "max") => Ok(None),
+            (_, v) => match v.parse() {
+                Ok(limit) if self.version == CgroupVersion::V1 && limit >= V1_UNLIMITED => Ok(None),
+                Ok(limit) => Ok(Some(limit)),
+                Err(_) => Err(invalid_data(name, &value)),
+            },
+        }
+    }
+
+    /// Get the memory usage and limits.
+    pub fn stats(&self) -> Result<MemoryStats> {
+        match self.version {
+            CgroupVersion::V2 => Ok(MemoryStats {
+                usage: self.read_u64("memory.current")?,
+                limit: self.read_limit("memory.max")?,
+                high: self.read_limit("memory.high")?,
+                peak: optional(self.read_u64("memory.peak"))?,
+                swap_usage: optional(self.read_u64("memory.swap.current"))?,
+            }),
+            CgroupVersion::V1 => {
+                let usage = self.read_u64("memory.usage_in_bytes")?;
+                Ok(MemoryStats {
+                    usage,
+                    limit: self.read_limit("memory.limit_in_bytes")?,
+                    high: self.read_limit("memory.soft_limit_in_bytes")?,
+                    peak: optional(self.read_u64("memory.max_usage_in_bytes"))?,
+                    swap_usage: optional(self.read_u64("memory.memsw.usage_in_bytes"))?
+                        .map(|memsw| memsw.saturating_sub(usage)),
+                })
+            }
+        }
+    }
+
+    /// Get the cumulative counters of memory events.
+    pub fn events(&self) -> Result<MemoryEvents> {
+        match self.version {
+            CgroupVersion::V2 => {
+                let events = parse_flat_keyed(&self.read("memory.events")?, "memory.events")?;
+                let get = |key: &str| events.get(key).copied().unwrap_or(0);
+                Ok(MemoryEvents {
+                    low: get("low"),
+                    high: get("high"),
+                    max: get("max"),
+                    oom: get("oom"),
+                    oom_kill: get("oom_kill"),
+                })
+            }
+            CgroupVersion::V1 => {
+                let oom_control =
+                    parse_flat_keyed(&self.read("memory.oom_control")?, "memory.oom_control")?;
+                Ok(MemoryEvents {
+                    max: self.read_u64("memory.failcnt")?,
+                    oom_kill: oom_control.get("oom_kill").copied().unwrap_or(0),
+                    ..Default::default()
+                })
+            }
+        }
+    }
+
+    /// Get the breakdown of `memory.stat`, e.g. `anon`, `file` and `shmem` of v2
+    /// or `rss`, `cache` and `shmem` of v1.
+    pub fn memory_stat(&self) -> Result<HashMap<String, u64>> {
+        parse_flat_keyed(&self.read("memory.stat")?, "memory.stat")
+    }
+}
+
+/// A tracker of memory events like `cpu::ProcessStat`.
+pub struct MemoryEventStat {
+    cgroup: MemoryCgroup,
+    last_events: MemoryEvents,
+}
+
+impl MemoryEventStat {
+    /// return a tracker of the memory cgroup of current process
+    pub fn cur() -> Result<Self> {
+        Self::build(MemoryCgroup::current()?)
+    }
+
+    /// return a tracker of the memory cgroup `cgroup`
+    pub fn build(cgroup: MemoryCgroup) -> Result<Self> {
+        let last_events = cgroup.events()?;
+        Ok(MemoryEventStat {
+            cgroup,
+            last_events,
+        })
+    }
+
+    /// return the events happened since the last call, e.g. a non-zero `oom_kill` means
+    /// some processes in the cgroup have been killed.
+    pub fn events(&mut self) -> Result<MemoryEvents> {
+        let cur = self.cgroup.events()?;
+        let last = std::mem::replace(&mut self.last_events, cur);
+        Ok(MemoryEvents {
+            low: cur.low.saturating_sub(last.low),
+            high: cur.high.saturating_sub(last.high),
+            max: cur.max.saturating_sub(last.max),
+            oom: cur.oom.saturating_sub(last.oom),
+            oom_kill: cur.oom_kill.saturating_sub(last.oom_kill),
+        })
+    }
+}
+
+fn invalid_data(name: &str, value: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid value of {}: {}", name, value.trim()),
+    )
+}
+
+fn optional<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(v) => Ok(Some(v)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parse lines like `oom_kill 0`.
+fn parse_flat_keyed(content: &str, name: &str) -> Result<HashMap<String, u64>> {
+    content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next().and_then(|v| v.parse().ok())) {
+                (Some(key), Some(value)) => Ok((key.to_string(), value)),
+                _ => Err(invalid_data(name, line)),
+            }
+        })
+        .collect()
+}
+
+/// Find the memory cgroup directory by the content of `/proc/self/cgroup` and `/proc/self/mountinfo`.
+fn locate(cgroup: &str, mountinfo: &str) -> Option<MemoryCgroup> {
+    // hierarchy-ID:controller-list:cgroup-path
+    let entries = cgroup
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, ':');
+            Some((parts.next()?, parts.next()?, parts.next()?))
+        })
+        .collect::<Vec<_>>();
+    // mount-ID parent-ID major:minor root mount-point options [optional-fields] - fstype source super-options
+    let mounts = mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (left, right) = line.split_once(" - ")?;
+            let left = left.split(' ').collect::<Vec<_>>();
+            let right = right.split(' ').collect::<Vec<_>>();
+            Some((
+                *left.get(3)?,
+                *left.get(4)?,
+                *right.first()?,
+                *right.get(2)?,
+            ))
+        })
+        .collect::<Vec<_>>();
+    let join = |root: &str, mount_point: &str, path: &str| {
+        let path = path.strip_prefix(root).unwrap_or(path);
+        Path::new(mount_point).join(path.trim_start_matches('/'))
+    };
+
+    let v1 = entries
+        .iter()
+        .find(|(_, controllers, _)| controllers.split(',').any(|c| c == "memory"));
+    if let Some((_, _, path)) = v1 {
+        let mount = mounts.iter().find(|(_, _, fstype, options)| {
+            *fstype == "cgroup" && options.split(',').any(|o| o == "memory")
+        });
+        if let Some((root, mount_point, _, _)) = mount {
+            return Some(MemoryCgroup::from_path(
+                CgroupVersion::V1,
+                join(root, mount_point, path),
+            ));
+        }
+    }
+
+    let (_, _, path) = entries.iter().find(|(id, _, _)| *id == "0")?;
+    let (root, mount_point, _, _) = mounts
+        .iter()
+        .find(|(_, _, fstype, _)| *fstype == "cgroup2")?;
+    Some(MemoryCgroup::from_path(
+        CgroupVersion::V2,
+        join(root, mount_point, path),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let mountinfo = "32 24 0:28 / /sys/fs/cgroup rw,relatime - tmpfs tmpfs rw,mode=755
+36 32 0:32 / /sys/fs/cgroup/memory rw,relatime - cgroup cgroup rw,memory
+42 32 0:38 / /sys/fs/cgroup/unified rw,relatime - cgroup2 cgroup2 rw
+";
+        let cgroup = "4:memory:/docker/abc\n0::/docker/abc\n";
+        assert_eq!(
+            locate(cgroup, mountinfo).unwrap(),
+            MemoryCgroup::from_path(CgroupVersion::V1, "/sys/fs/cgroup/memory/docker/abc")
+        );
+
+        let mountinfo =
+            "30 23 0:26 /user.slice /sys/fs/cgroup rw,nosuid shared:4 - cgroup2 cgroup2 rw\n";
+        let cgroup = "0::/user.slice/app.scope\n";
+        assert_eq!(
+            locate(cgroup, mountinfo).unwrap(),
+            MemoryCgroup::from_path(CgroupVersion::V2, "/sys/fs/cgroup/app.scope")
+        );
+        assert_eq!(locate("", mountinfo), None);
+    }
+
+    #[test]
+    fn test_cgroup_v2_files() {
+        let dir = std::env::temp_dir().join(format!("perf_monitor_cgroup_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in [
+            ("memory.current", "1048576\n"),
+            ("memory.max", "4194304\n"),
+            ("memory.high", "max\n"),
+            ("memory.events", "low 0\nhigh 3\nmax 2\noom 1\noom_kill 1\n"),
+            ("memory.stat", "anon 524288\nfile 524288\n"),
+        ] {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        let cgroup = MemoryCgroup::from_path(CgroupVersion::V2, &dir);
+        let stats = cgroup.stats().unwrap();
+        assert_eq!(
+            stats,
+            MemoryStats {
+                usage: 1048576,
+                limit: Some(4194304),
+                high: None,
+                peak: None,
+                swap_usage: None,
+            }
+        );
+        assert_eq!(stats.usage_ratio(), Some(0.25));
+        assert_eq!(cgroup.memory_stat().unwrap()["anon"], 524288);
+
+        let mut stat = MemoryEventStat::build(cgroup).unwrap();
+        fs::write(
+            dir.join("memory.events"),
+            "low 0\nhigh 5\nmax 2\noom 2\noom_kill 3\n",
+        )
+        .unwrap();
+        assert_eq!(
+            stat.events().unwrap(),
+            MemoryEvents {
+                high: 2,
+                oom: 1,
+                oom_kill: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(stat.events().unwrap(), MemoryEvents::default());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_current() {
+        // there may be no memory cgroup, e.g. in some sandboxes.
+        if let Ok(cgroup) = MemoryCgroup::current() {
+            if let Ok(stats) = cgroup.stats() {
+                assert!(stats.usage > 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! A wrapper around glibc malloc introspection APIs.
+//!
+//! `mallinfo2` is added in glibc 2.33, it's looked up at runtime so that older glibc still works.
+
+use super::get_process_memory_info;
+use std::{
+    io::{self, Error, ErrorKind},
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A Wrapper around `struct mallinfo2`, originally defined at `malloc.h`.
+///
+/// The fields sum up all arenas, e.g. `arena` is the bytes obtained from the system for arenas,
+/// `hblkhd` is the bytes of chunks allocated by `mmap`, `uordblks` is the bytes in use,
+/// `fordblks` is the free bytes held by malloc, except that `keepcost` is the bytes at the top
+/// of the main arena which can be released by `malloc_trim`.
+pub type MallInfo = libc::mallinfo2;
+
+// `dlsym` never returns 1, so it marks the symbol not looked up yet.
+const UNRESOLVED: usize = 1;
+
+static MALLINFO2: AtomicUsize = AtomicUsize::new(UNRESOLVED);
+
+/// Get the statistics of all arenas and mmapped chunks,
+/// or `None` if glibc is older than 2.33, which doesn't have `mallinfo2`.
+pub fn mallinfo2() -> Option<MallInfo> {
+    let mut addr = MALLINFO2.load(Ordering::Relaxed);
+    if addr == UNRESOLVED {
+        addr = unsafe { libc::dlsym(libc::RTLD_DEFAULT, b"mallinfo2\0".as_ptr() as _) } as usize;
+        MALLINFO2.store(addr, Ordering::Relaxed);
+    }
+    if addr == 0 {
+        return None;
+    }
+    let mallinfo2: unsafe extern "C" fn() -> MallInfo = unsafe { mem::transmute(addr) };
+    Some(unsafe { mallinfo2() })
+}
+
+/// Statistics of an arena, parsed from the output of `malloc_info`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArenaInfo {
+    /// the number of this arena, the main arena is 0.
+    pub index: usize,
+    /// the number of free chunks in fastbins.
+    pub fast_count: u64,
+    /// the bytes of free chunks in fastbins.
+    pub fast_bytes: u64,
+    /// the number of free chunks in other bins.
+    pub rest_count: u64,
+    /// the bytes of free chunks in other bins.
+    pub rest_bytes: u64,
+    /// the bytes obtained from the system.
+    pub system_current: u64,
+    /// the max bytes ever obtained from the system.
+    pub system_max: u64,
+    /// the bytes of the address space.
+    pub aspace_total: u64,
+    /// the bytes of the address space which is accessible.
+    pub aspace_mprotect: u64,
+}
+
+impl ArenaInfo {
+    /// The bytes held by the arena but free, which are either fragments or not returned to the system.
+    pub fn free_bytes(&self) -> u64 {
+        self.fast_bytes + self.rest_bytes
+    }
+}
+
+/// The output of `malloc_info` returned by `get_malloc_info`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MallocInfo {
+    pub arenas: Vec<ArenaInfo>,
+    /// the number of chunks allocated by `mmap`.
+    pub mmap_count: u64,
+    /// the bytes of chunks allocated by `mmap`.
+    pub mmap_bytes: u64,
+}
+
+/// Get per-arena statistics by parsing the XML written by `malloc_info`.
+pub fn get_malloc_info() -> io::Result<MallocInfo> {
+    parse_malloc_info(&malloc_info_xml()?)
+}
+
+fn malloc_info_xml() -> io::Result<String> {
+    let mut buf: *mut libc::c_char = std::ptr::null_mut();
+    let mut len: libc::size_t = 0;
+    unsafe {
+        let stream = libc::open_memstream(&mut buf, &mut len);
+        if stream.is_null() {
+            return Err(Error::last_os_error());
+        }
+        let ret = libc::malloc_info(0, stream);
+        let err = Error::last_os_error();
+        // `buf` and `len` are updated when the stream is closed.
+        libc::fclose(stream);
+        let xml = if buf.is_null() {
+            String::new()
+        } else {
+            let xml = std::slice::from_raw_parts(buf as *const u8, len);
+            let xml = String::from_utf8_lossy(xml).into_owned();
+            libc::free(buf as *mut libc::c_void);
+            xml
+        };
+        if ret != 0 {
+            return Err(err);
+        }
+        Ok(xml)
+    }
+}
+
+/// Get the value of attribute `name` of an element like `<total type="fast" count="0" size="0"/>`.
+fn attr<'a>(element: &'a str, name: &str) -> Option<&'a str> {
+    let start = element.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let len = element[start..].find('"')?;
+    Some(&element[start..start + len])
+}
+
+fn attr_u64(element: &str, name: &str) -> io::Result<u64> {
+    attr(element, name)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid attribute {} of {} in malloc_info", name, element),
+            )
+        })
+}
+
+fn parse_malloc_info(xml: &str) -> io::Result<MallocInfo> {
+    let mut info = MallocInfo::default();
+    let mut arena: Option<ArenaInfo> = None;
+    for element in xml.lines().map(str::trim) {
+        if element.starts_with("<heap ") {
+            arena = Some(ArenaInfo {
+                index: attr_u64(element, "nr")? as usize,
+                ..Default::default()
+            });
+        } else if element.starts_with("</heap>") {
+            info.arenas.extend(arena.take());
+        } else if element.starts_with("<total ") {
+            let count = attr_u64(element, "count")?;
+            let size = attr_u64(element, "size")?;
+            match (arena.as_mut(), attr(element, "type")) {
+                (Some(arena), Some("fast")) => {
+                    arena.fast_count = count;
+                    arena.fast_bytes = size;
+                }
+                (Some(arena), Some("rest")) => {
+                    arena.rest_count = count;
+                    arena.rest_bytes = size;
+                }
+                (None, Some("mmap")) => {
+                    info.mmap_count = count;
+                    info.mmap_bytes = size;
+                }
+                _ => {}
+            }
+        } else if let Some(arena) = arena.as_mut() {
+            let (field, size) = if element.starts_with("<system ") {
+                match attr(element, "type") {
+                    Some("current") => (&mut arena.system_current, attr_u64(element, "size")?),
+                    Some("max") => (&mut arena.system_max, attr_u64(element, "size")?),
+                    _ => continue,
+                }
+            } else if element.starts_with("<aspace ") {
+                match attr(element, "type") {
+                    Some("total") => (&mut arena.aspace_total, attr_u64(element, "size")?),
+                    Some("mprotect") => (&mut arena.aspace_mprotect, attr_u64(element, "size")?),
+                    _ => continue,
+                }
+            } else {
+                continue;
+            };
+            *field = size;
+        }
+    }
+    Ok(info)
+}
+
+/// Release free memory held by malloc to the system, keeping `pad` bytes at the top of the heap.
+///
+/// Return the bytes returned to the system, which is the decrease of the resident set size,
+/// so it's approximate when other threads are running.
+pub fn malloc_trim(pad: usize) -> io::Result<u64> {
+    let before = get_process_memory_info()?.resident_set_size;
+    unsafe { libc::malloc_trim(pad) };
+    let after = get_process_memory_info()?.resident_set_size;
+    // the resident set size of `/proc/self/statm` is in pages.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    Ok(before.saturating_sub(after) * page_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mallinfo2() {
+        // glibc of the test environment is newer than 2.33.
+        let info = mallinfo2().unwrap();
+        assert!(info.arena > 0);
+        assert!(info.uordblks > 0);
+    }
+
+    #[test]
+    fn test_parse_malloc_info() {
+        let xml = r#"<malloc version="1">
+<heap nr="0">
+<sizes>
+  <size from="17" to="32" total="64" count="2"/>
+  <unsorted from="1041" to="1041" total="1041" count="1"/>
+</sizes>
+<total type="fast" count="2" size="64"/>
+<total type="rest" count="1" size="1041"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</heap>
+<heap nr="1">
+<sizes>
+</sizes>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="4096"/>
+<system type="max" size="8192"/>
+<aspace type="total" size="65536"/>
+<aspace type="mprotect" size="4096"/>
+<aspace type="subheaps" size="1"/>
+</heap>
+<total type="fast" count="2" size="64"/>
+<total type="rest" count="1" size="1041"/>
+<total type="mmap" count="3" size="3145728"/>
+<system type="current" size="139264"/>
+<system type="max" size="143360"/>
+<aspace type="total" size="200704"/>
+<aspace type="mprotect" size="139264"/>
+</malloc>
+"#;
+        let info = parse_malloc_info(xml).unwrap();
+        assert_eq!(info.mmap_count, 3);
+        assert_eq!(info.mmap_bytes, 3145728);
+        assert_eq!(
+            info.arenas,
+            vec![
+                ArenaInfo {
+                    index: 0,
+                    fast_count: 2,
+                    fast_bytes: 64,
+                    rest_count: 1,
+                    rest_bytes: 1041,
+                    system_current: 135168,
+                    system_max: 135168,
+                    aspace_total: 135168,
+                    aspace_mprotect: 135168,
+                },
+                ArenaInfo {
+                    index: 1,
+                    system_current: 4096,
+                    system_max: 8192,
+                    aspace_total: 65536,
+                    aspace_mprotect: 4096,
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(info.arenas[0].free_bytes(), 1105);
+    }
+
+    #[test]
+    fn test_get_malloc_info_and_trim() {
+        let info = get_malloc_info().unwrap();
+        assert!(!info.arenas.is_empty());
+        assert_eq!(info.arenas[0].index, 0);
+        assert!(info.arenas[0].system_current > 0);
+        malloc_trim(0).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use super::{get_process_memory_info, CountingAllocator};
+use std::{fmt, io::Result};
+
+/// A breakdown of the heap returned by `get_heap_report`, all fields are in bytes.
+///
+/// It combines `CountingAllocator::get_allocated()`, allocator-level stats and the resident set size.
+/// Stats of the allocator are available only with glibc 2.33 or later (by `mallinfo2`) for now.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeapReport {
+    /// bytes of live Rust objects, `None` if `CountingAllocator` is not enable.
+    pub live: Option<u64>,
+    /// bytes in use reported by the allocator, including chunk headers, padding
+    /// and allocations not made by Rust, e.g. by C libraries, `None` if the allocator is not supported.
+    pub allocator_in_use: Option<u64>,
+    /// bytes held by the allocator but free, which are fragments or not returned to the OS.
+    pub allocator_free: Option<u64>,
+    /// the resident set size of current process.
+    pub resident: u64,
+}
+
+impl HeapReport {
+    /// Bytes used by the allocator beyond live Rust objects.
+    pub fn overhead(&self) -> Option<u64> {
+        Some(self.allocator_in_use?.saturating_sub(self.live?))
+    }
+
+    /// The ratio of free bytes held by the allocator to all bytes held by it.
+    pub fn fragmentation(&self) -> Option<f64> {
+        let free = self.allocator_free? as f64;
+        let held = free + self.allocator_in_use? as f64;
+        Some(if held > 0.0 { free / held } else { 0.0 })
+    }
+
+    /// Resident bytes out of the heap, e.g. code, stacks and other mappings.
+    pub fn non_heap(&self) -> Option<u64> {
+        Some(
+            self.resident
+                .saturating_sub(self.allocator_in_use? + self.allocator_free?),
+        )
+    }
+}
+
+impl fmt::Display for HeapReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn bytes(value: Option<u64>) -> String {
+            value.map_or_else(|| "n/a".to_string(), |v| v.to_string())
+        }
+        writeln!(f, "live rust objects: {}", bytes(self.live))?;
+        writeln!(f, "allocator overhead: {}", bytes(self.overhead()))?;
+        writeln!(f, "held but free: {}", bytes(self.allocator_free))?;
+        writeln!(f, "non-heap resident: {}", bytes(self.non_heap()))?;
+        writeln!(f, "resident: {}", self.resident)
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn allocator_stats() -> (Option<u64>, Option<u64>) {
+    let info = match super::glibc::mallinfo2() {
+        Some(info) => info,
+        None => return (None, None),
+    };
+    // `uordblks` and `fordblks` cover all arenas, `hblkhd` are chunks allocated by mmap.
+    (
+        Some((info.uordblks + info.hblkhd) as u64),
+        Some(info.fordblks as u64),
+    )
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn allocator_stats() -> (Option<u64>, Option<u64>) {
+    (None, None)
+}
+
+/// Get a breakdown of the heap of current process.
+pub fn get_heap_report() -> Result<HeapReport> {
+    let live =
+        CountingAllocator::is_enable().then(|| CountingAllocator::get_allocated().max(0) as u64);
+    let (allocator_in_use, allocator_free) = allocator_stats();
+    let resident = get_process_memory_info()?.resident_set_size;
+    // the resident set size of `/proc/self/statm` is in pages.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let resident = resident * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    Ok(HeapReport {
+        live,
+        allocator_in_use,
+        allocator_free,
+        resident,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heap_report() {
+        let report = HeapReport {
+            live: Some(600),
+            allocator_in_use: Some(1000),
+            allocator_free: Some(1000),
+            resident: 5000,
+        };
+        assert_eq!(report.overhead(), Some(400));
+        assert_eq!(report.fragmentation(), Some(0.5));
+        assert_eq!(report.non_heap(), Some(3000));
+        assert!(report.to_string().contains("allocator overhead: 400"));
+
+        let report = get_heap_report().unwrap();
+        assert!(report.resident > 0);
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        assert_eq!(
+            report.allocator_in_use.is_some(),
+            crate::mem::glibc::mallinfo2().is_some()
+        );
+        assert!(report.allocator_in_use.map_or(true, |bytes| bytes > 0));
+    }
+}
@@ -0,0 +1,175 @@
+//! Statistics of jemalloc read by the mallctl API.
+//!
+//! They are statistics of the jemalloc linked by `tikv-jemalloc-sys` 0.6,
+//! which is the one used by `tikv-jemallocator` 0.6 as the global allocator.
+
+use super::ProcessMemoryInfo;
+use std::{ffi::CString, io, mem, os::raw::c_char, ptr};
+
+/// Memory info of jemalloc returned by `get_jemalloc_memory_info`, all fields are in bytes.
+///
+/// `ProcessMemoryInfo` is in pages on Linux and Android, so don't compare `resident` with
+/// `ProcessMemoryInfo::resident_set_size` directly, use `JemallocMemoryInfo::process_memory_info`
+/// or multiply the latter by the page size.
+#[derive(Debug, Clone, Default)]
+pub struct JemallocMemoryInfo {
+    /// the bytes allocated by the application.
+    pub allocated: u64,
+    /// the bytes in active pages allocated by the application, which is a multiple of the page size.
+    pub active: u64,
+    /// the bytes dedicated to metadata.
+    pub metadata: u64,
+    /// the bytes in physically resident data pages mapped by jemalloc, including unused dirty pages.
+    pub resident: u64,
+    /// the bytes in active extents mapped by jemalloc.
+    pub mapped: u64,
+    /// the bytes in virtual memory mappings which were retained rather than returned to the OS.
+    pub retained: u64,
+    /// statistics of initialized arenas.
+    pub arenas: Vec<JemallocArenaInfo>,
+}
+
+impl JemallocMemoryInfo {
+    /// The memory mapped by jemalloc in the units of `get_process_memory_info`,
+    /// so that it can be compared with the memory of the process side by side.
+    ///
+    /// `resident_set_size` is `resident`, and `virtual_memory_size` is `mapped` plus `retained`.
+    pub fn process_memory_info(&self) -> ProcessMemoryInfo {
+        // `/proc/self/statm` is measured in pages.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let unit = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let unit = 1;
+        // there are more fields on other platforms.
+        #[allow(clippy::needless_update)]
+        ProcessMemoryInfo {
+            resident_set_size: self.resident / unit,
+            virtual_memory_size: (self.mapped + self.retained) / unit,
+            ..Default::default()
+        }
+    }
+}
+
+/// Memory info of an arena.
+#[derive(Debug, Clone, Default)]
+pub struct JemallocArenaInfo {
+    /// the index of this arena.
+    pub index: usize,
+    /// the number of threads assigned to this arena.
+    pub threads: u32,
+    /// the bytes allocated by small size classes.
+    pub small_allocated: u64,
+    /// the bytes allocated by large size classes.
+    pub large_allocated: u64,
+    /// the bytes in active pages.
+    pub active: u64,
+    /// the bytes in unused dirty pages, which will be purged.
+    pub dirty: u64,
+    /// the bytes in unused muzzy pages, which are purged lazily.
+    pub muzzy: u64,
+    /// the bytes in physically resident data pages mapped by this arena.
+    pub resident: u64,
+    /// the bytes in active extents mapped by this arena.
+    pub mapped: u64,
+    /// the bytes in virtual memory mappings which were retained rather than returned to the OS.
+    pub retained: u64,
+}
+
+unsafe fn mallctl<T: Copy>(name: &str, new: Option<T>) -> io::Result<T> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut value = mem::MaybeUninit::<T>::zeroed();
+    let mut len = mem::size_of::<T>();
+    let mut new = new;
+    let (newp, newlen) = match new.as_mut() {
+        Some(new) => (new as *mut T as *mut _, mem::size_of::<T>()),
+        None => (ptr::null_mut(), 0),
+    };
+    let ret = tikv_jemalloc_sys::mallctl(
+        name.as_ptr() as *const c_char,
+        value.as_mut_ptr() as *mut _,
+        &mut len,
+        newp,
+        newlen,
+    );
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(value.assume_init())
+}
+
+fn read_size(name: &str) -> io::Result<u64> {
+    unsafe { mallctl::<libc::size_t>(name, None) }.map(|v| v as u64)
+}
+
+fn arena_info(index: usize, page_size: u64) -> io::Result<JemallocArenaInfo> {
+    let key = |name: &str| format!("stats.arenas.{}.{}", index, name);
+    Ok(JemallocArenaInfo {
+        index,
+        threads: unsafe { mallctl::<libc::c_uint>(&key("nthreads"), None)? },
+        small_allocated: read_size(&key("small.allocated"))?,
+        large_allocated: read_size(&key("large.allocated"))?,
+        active: read_size(&key("pactive"))? * page_size,
+        dirty: read_size(&key("pdirty"))? * page_size,
+        muzzy: read_size(&key("pmuzzy"))? * page_size,
+        resident: read_size(&key("resident"))?,
+        mapped: read_size(&key("mapped"))?,
+        retained: read_size(&key("retained"))?,
+    })
+}
+
+/// Get the memory info of jemalloc.
+///
+/// Statistics are cached by jemalloc, so the epoch is advanced first to refresh them.
+pub fn get_jemalloc_memory_info() -> io::Result<JemallocMemoryInfo> {
+    unsafe { mallctl::<u64>("epoch", Some(1))? };
+    let page_size = read_size("arenas.page")?;
+    let narenas = unsafe { mallctl::<libc::c_uint>("arenas.narenas", None)? } as usize;
+    let mut arenas = vec![];
+    for index in 0..narenas {
+        if unsafe { mallctl::<bool>(&format!("arena.{}.initialized", index), None)? } {
+            arenas.push(arena_info(index, page_size)?);
+        }
+    }
+    Ok(JemallocMemoryInfo {
+        allocated: read_size("stats.allocated")?,
+        active: read_size("stats.active")?,
+        metadata: read_size("stats.metadata")?,
+        resident: read_size("stats.resident")?,
+        mapped: read_size("stats.mapped")?,
+        retained: read_size("stats.retained")?,
+        arenas,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_jemalloc_memory_info() {
+        let size = 4 << 20;
+        let ptr = unsafe { tikv_jemalloc_sys::malloc(size) };
+        assert!(!ptr.is_null());
+        let info = get_jemalloc_memory_info().unwrap();
+        unsafe { tikv_jemalloc_sys::free(ptr) };
+
+        assert!(info.allocated >= size as u64);
+        assert!(info.active >= info.allocated);
+        assert!(info.resident > 0);
+        assert!(info.mapped >= info.active);
+        assert!(!info.arenas.is_empty());
+        assert!(
+            info.arenas
+                .iter()
+                .map(|arena| arena.small_allocated + arena.large_allocated)
+                .sum::<u64>()
+                >= size as u64
+        );
+
+        // memory mapped by jemalloc is a part of the process, in the same units.
+        let jemalloc = info.process_memory_info();
+        let process = crate::mem::get_process_memory_info().unwrap();
+        assert!(jemalloc.resident_set_size > 0);
+        assert!(jemalloc.virtual_memory_size <= process.virtual_memory_size);
+    }
+}
@@ -1,21 +1,93 @@
 //! This sub-mod provides some facilities about memory performance profiling.
 //! # Memory usage of current process
 //! There's a platform-related function called `get_process_memory_info` available on MacOS and Windows.
+//! `get_process_memory_info_pid` returns the same info of another process.
+//! `WorkingSetStat` and `estimate_working_set` estimate the bytes actually touched on Linux and Android.
 //! # Memory usage of ALL Rust allocations
-//! We provide a `CountingAllocator` that wraps the system allocator but tracks the bytes used by rust allocations.
+//! We provide a `CountingAllocator` that wraps the system allocator (or any other global allocator) but tracks the bytes used by rust allocations.
 //! This crate DOES NOT replace the global allocator by default. You need to make it as a `global_allocator` or enable the `allocation_counter` feature.
 //! ```ignore
 //! #[global_allocator]
-//! static _COUNTER: perf_monitor::mem::CountingAllocator = perf_monitor:mem::CountingAllocator;
+//! static _COUNTER: perf_monitor::mem::CountingAllocator =
+//!     perf_monitor::mem::CountingAllocator::new(std::alloc::System);
 //! ```
+//! `AllocScope` and `measure_alloc` measure the allocations of a piece of code on current thread.
+//! `get_heap_report` compares the bytes of live Rust objects with the allocator and the resident set size.
+//! `with_tag` charges allocations to a subsystem, if the allocator is built by `CountingAllocator::with_tagging()`.
+//! `CountingAllocator::enable_latency_sampling` keeps latency histograms of a sample of allocator calls.
+//! # Memory usage of the system
+//! `get_system_memory_info` reads `/proc/meminfo` on Linux and Android.
+//! In containers, `cgroup::MemoryCgroup` reads the limit and usage of the memory cgroup instead.
+//! `pressure::subscribe` notifies changes of the memory pressure level.
+//! # Allocator statistics
+//! `apple::heap` reads malloc zones on MacOS, `glibc` reads `mallinfo2` and `malloc_info` on Linux with glibc,
+//! and `jemalloc` reads the statistics of jemalloc with the `jemalloc` feature.
+//! # Page faults
+//! `ProcessFaultStat` and `ThreadFaultStat` report minor and major page faults the same way as `cpu::ProcessStat`.
 
 mod allocation_counter;
 
-pub use allocation_counter::CountingAllocator;
+#[cfg(feature = "backtrace")]
+pub use allocation_counter::Symbol;
+pub use allocation_counter::{
+    current_tag, measure_alloc, with_tag, AllocOp, AllocScope, AllocStats, CountingAllocator,
+    LargeAlloc, LatencyBucket, LatencyHistogram, ScopeStats, SizeBucket, Tag, TagGuard, TagStats,
+    ThreadAllocStats, MAX_TAGS, MAX_TRACKED_THREADS,
+};
+#[cfg(feature = "leak_tracker")]
+pub use allocation_counter::{LeakCheckpoint, LeakReport, LeakSite};
+
+mod heap_report;
+pub use heap_report::{get_heap_report, HeapReport};
 
 mod process_memory_info;
-pub use process_memory_info::{get_process_memory_info, ProcessMemoryInfo};
+pub use process_memory_info::{
+    get_process_memory_info, get_process_memory_info_pid, ProcessMemoryInfo,
+};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod system_memory_info;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use system_memory_info::{get_system_memory_info, SystemMemoryInfo};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod working_set;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use working_set::{estimate_working_set, WorkingSet, WorkingSetKind, WorkingSetStat};
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+mod page_faults;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+pub use page_faults::{get_process_page_faults, PageFaultRate, PageFaults, ProcessFaultStat};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use page_faults::{get_thread_page_faults, ThreadFaultStat};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[cfg_attr(doc, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+pub mod cgroup;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[cfg_attr(doc, doc(cfg(any(target_os = "linux", target_os = "android"))))]
+pub mod pressure;
 
 #[cfg(target_os = "macos")]
 #[cfg_attr(doc, doc(cfg(macos)))]
 pub mod apple;
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[cfg_attr(doc, doc(cfg(all(target_os = "linux", target_env = "gnu"))))]
+pub mod glibc;
+
+#[cfg(feature = "jemalloc")]
+#[cfg_attr(doc, doc(cfg(feature = "jemalloc")))]
+pub mod jemalloc;
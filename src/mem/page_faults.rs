@@ -0,0 +1,241 @@
+//! Minor and major page fault counters of current process and its threads.
+//!
+//! ## Bottom Layer Interface
+//! | platform | thread | process |
+//! | -- | -- | -- |
+//! | linux & android | [/proc/self/task/{tid}/stat][man5] | [/proc/self/stat][man5] |
+//! | macos & ios | - | [getrusage] |
+//!
+//! [man5]: https://man7.org/linux/man-pages/man5/proc.5.html
+//! [getrusage]: https://www.man7.org/linux/man-pages/man2/getrusage.2.html
+
+use std::{
+    io::{Error, ErrorKind, Result},
+    mem,
+    time::Instant,
+};
+
+/// Cumulative page fault counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageFaults {
+    /// faults which have not required loading a memory page from disk.
+    pub minor: u64,
+    /// faults which have required loading a memory page from disk.
+    pub major: u64,
+}
+
+impl PageFaults {
+    fn saturating_sub(self, other: Self) -> Self {
+        PageFaults {
+            minor: self.minor.saturating_sub(other.minor),
+            major: self.major.saturating_sub(other.major),
+        }
+    }
+}
+
+/// Page faults per second returned by `ProcessFaultStat::rate` and `ThreadFaultStat::rate`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PageFaultRate {
+    pub minor: f64,
+    pub major: f64,
+}
+
+/// Parse `minflt` and `majflt` out of the content of `/proc/[pid]/stat` or `/proc/[pid]/task/[tid]/stat`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn parse_stat(stat: &str) -> Result<PageFaults> {
+    // `comm` (the 2nd field) may contain spaces and parentheses, skip to the last ')'.
+    let fields = stat
+        .rfind(')')
+        .map(|pos| &stat[pos + 1..])
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid comm in stat"))?;
+    // fields after comm start from `state`(3), so `minflt`(10) is the 7th and `majflt`(12) is the 9th.
+    let mut fields = fields.split_whitespace();
+    let minor = fields.nth(7).and_then(|s| s.parse().ok());
+    let major = fields.nth(1).and_then(|s| s.parse().ok());
+    match (minor, major) {
+        (Some(minor), Some(major)) => Ok(PageFaults { minor, major }),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Invalid minflt/majflt in stat",
+        )),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_process_page_faults_impl() -> Result<PageFaults> {
+    parse_stat(&std::fs::read_to_string("/proc/self/stat")?)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn get_process_page_faults_impl() -> Result<PageFaults> {
+    let mut usage = mem::MaybeUninit::<libc::rusage>::uninit();
+    let ret = unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    let usage = unsafe { usage.assume_init() };
+    Ok(PageFaults {
+        minor: usage.ru_minflt as u64,
+        major: usage.ru_majflt as u64,
+    })
+}
+
+/// Get the cumulative page faults of current process.
+pub fn get_process_page_faults() -> Result<PageFaults> {
+    get_process_page_faults_impl()
+}
+
+/// Get the cumulative page faults of the specified thread in current process.
+///
+/// `tid` is the kernel thread id returned by `gettid`, which is **NOT** `std::thread::ThreadId`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_thread_page_faults(tid: libc::pid_t) -> Result<PageFaults> {
+    parse_stat(&std::fs::read_to_string(format!(
+        "/proc/self/task/{}/stat",
+        tid
+    ))?)
+}
+
+fn rate(faults: PageFaults, old_now: Instant, now: Instant) -> PageFaultRate {
+    let real_time = now.saturating_duration_since(old_now).as_secs_f64();
+    if real_time == 0.0 {
+        return PageFaultRate::default();
+    }
+    PageFaultRate {
+        minor: faults.minor as f64 / real_time,
+        major: faults.major as f64 / real_time,
+    }
+}
+
+/// A struct to monitor process page faults
+pub struct ProcessFaultStat {
+    now: Instant,
+    faults: PageFaults,
+}
+
+impl ProcessFaultStat {
+    /// return a monitor of current process
+    pub fn cur() -> Result<Self> {
+        Ok(ProcessFaultStat {
+            now: Instant::now(),
+            faults: get_process_page_faults()?,
+        })
+    }
+
+    /// return the page faults from last invoke,
+    /// or when this struct created if it is the first invoke.
+    pub fn faults(&mut self) -> Result<PageFaults> {
+        let old_faults = mem::replace(&mut self.faults, get_process_page_faults()?);
+        self.now = Instant::now();
+        Ok(self.faults.saturating_sub(old_faults))
+    }
+
+    /// return the page faults per second from last invoke,
+    /// or when this struct created if it is the first invoke.
+    pub fn rate(&mut self) -> Result<PageFaultRate> {
+        let old_now = self.now;
+        let faults = self.faults()?;
+        Ok(rate(faults, old_now, self.now))
+    }
+}
+
+/// A struct to monitor thread page faults
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub struct ThreadFaultStat {
+    tid: libc::pid_t,
+    now: Instant,
+    faults: PageFaults,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl ThreadFaultStat {
+    /// return a monitor of current thread.
+    pub fn cur() -> Result<Self> {
+        Self::build(super::allocation_counter::os_thread_id() as libc::pid_t)
+    }
+
+    /// return a monitor of specified thread.
+    ///
+    /// `tid` is the kernel thread id returned by `gettid`, which is **NOT** `std::thread::ThreadId`.
+    pub fn build(tid: libc::pid_t) -> Result<Self> {
+        Ok(ThreadFaultStat {
+            tid,
+            now: Instant::now(),
+            faults: get_thread_page_faults(tid)?,
+        })
+    }
+
+    /// return the page faults from last invoke,
+    /// or when this struct created if it is the first invoke.
+    pub fn faults(&mut self) -> Result<PageFaults> {
+        let old_faults = mem::replace(&mut self.faults, get_thread_page_faults(self.tid)?);
+        self.now = Instant::now();
+        Ok(self.faults.saturating_sub(old_faults))
+    }
+
+    /// return the page faults per second from last invoke,
+    /// or when this struct created if it is the first invoke.
+    pub fn rate(&mut self) -> Result<PageFaultRate> {
+        let old_now = self.now;
+        let faults = self.faults()?;
+        Ok(rate(faults, old_now, self.now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_parse_stat() {
+        let stat = "1234 (a (b) c) S 1 1234 1234 0 -1 4194560 42 0 7 0 1 2 0 0 20 0 1 0 1 2 3";
+        let faults = parse_stat(stat).unwrap();
+        assert_eq!(
+            faults,
+            PageFaults {
+                minor: 42,
+                major: 7
+            }
+        );
+    }
+
+    #[test]
+    fn test_rate() {
+        let faults = PageFaults {
+            minor: 20,
+            major: 2,
+        };
+        let now = Instant::now();
+        let rate = rate(faults, now, now + std::time::Duration::from_secs(2));
+        assert_eq!(rate.minor, 10.0);
+        assert_eq!(rate.major, 1.0);
+        assert_eq!(super::rate(faults, now, now), PageFaultRate::default());
+    }
+
+    #[test]
+    fn test_process_minor_faults() {
+        let mut stat = ProcessFaultStat::cur().unwrap();
+        // touch every page of a fresh mapping to trigger minor faults.
+        let mut buf = vec![0u8; 64 * 1024 * 1024];
+        for i in (0..buf.len()).step_by(4096) {
+            buf[i] = 1;
+        }
+        std::hint::black_box(&buf);
+        let faults = stat.faults().unwrap();
+        assert!(faults.minor > 1000);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_thread_minor_faults() {
+        let mut stat = ThreadFaultStat::cur().unwrap();
+        let mut buf = vec![0u8; 64 * 1024 * 1024];
+        for i in (0..buf.len()).step_by(4096) {
+            buf[i] = 1;
+        }
+        std::hint::black_box(&buf);
+        let rate = stat.rate().unwrap();
+        assert!(rate.minor > 0.0);
+    }
+}
@@ -0,0 +1,323 @@
+//! Memory pressure notifications.
+//!
+//! A subscription watches memory pressure on a background thread and invokes the callback
+//! whenever the level changes, e.g. to shed caches on `Warning`.
+//!
+//! On Linux the level comes from PSI triggers of the memory cgroup (cgroup v2) or of the system
+//! (`/proc/pressure/memory`), and from new events of the memory cgroup like `high` and `oom`.
+//! The levels are the same as those of macOS dispatch memory-pressure sources.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PressureLevel {
+    Normal = 0,
+    Warning = 1,
+    Critical = 2,
+}
+
+impl PressureLevel {
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => PressureLevel::Normal,
+            1 => PressureLevel::Warning,
+            _ => PressureLevel::Critical,
+        }
+    }
+}
+
+/// Options of `subscribe`.
+#[derive(Debug, Clone)]
+pub struct PressureOptions {
+    /// report `Warning` if some tasks are stalled on memory for this long in a window.
+    pub warning_stall: Duration,
+    /// report `Critical` if all non-idle tasks are stalled on memory for this long in a window.
+    pub critical_stall: Duration,
+    /// the PSI window, which must be between 500ms and 10s, and a multiple of 2s for unprivileged users.
+    /// The level goes down if there's no pressure for two windows, since a trigger fires at most once per window.
+    pub window: Duration,
+    /// the interval of polling the events of the memory cgroup.
+    pub poll_interval: Duration,
+}
+
+impl Default for PressureOptions {
+    fn default() -> Self {
+        PressureOptions {
+            warning_stall: Duration::from_millis(100),
+            critical_stall: Duration::from_millis(200),
+            window: Duration::from_secs(2),
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A subscription returned by `subscribe`, which is cancelled when dropped.
+///
+/// Dropping it waits for the background thread, which takes up to `PressureOptions::poll_interval`.
+pub struct Subscription {
+    level: Arc<AtomicU8>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Subscription {
+    /// Get the current level.
+    pub fn level(&self) -> PressureLevel {
+        PressureLevel::from_u8(self.level.load(Ordering::Relaxed))
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Subscribe to memory pressure, `callback` is invoked on a background thread with the new level
+/// whenever the level changes. The initial level is `Normal`.
+///
+/// Return an error if no source of memory pressure is available, e.g. neither PSI nor cgroup is supported.
+pub fn subscribe(
+    options: PressureOptions,
+    callback: impl FnMut(PressureLevel) + Send + 'static,
+) -> io::Result<Subscription> {
+    let sources = Sources::open(&options)?;
+    let level = Arc::new(AtomicU8::new(PressureLevel::Normal as u8));
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let level = level.clone();
+        let stop = stop.clone();
+        std::thread::Builder::new()
+            .name("memory-pressure".to_string())
+            .spawn(move || run(sources, options, &level, &stop, callback))?
+    };
+    Ok(Subscription {
+        level,
+        stop,
+        thread: Some(thread),
+    })
+}
+
+fn run(
+    mut sources: Sources,
+    options: PressureOptions,
+    level: &AtomicU8,
+    stop: &AtomicBool,
+    mut callback: impl FnMut(PressureLevel),
+) {
+    let mut last_fired = [None; 3];
+    let mut current = PressureLevel::Normal;
+    while !stop.load(Ordering::Relaxed) {
+        let fired = sources.poll(options.poll_interval);
+        let now = Instant::now();
+        for l in fired {
+            last_fired[l as usize] = Some(now);
+        }
+        let next = evaluate(&last_fired, now, options.window);
+        if next != current {
+            current = next;
+            level.store(next as u8, Ordering::Relaxed);
+            callback(next);
+        }
+    }
+}
+
+/// The highest level which has fired in the last two windows.
+///
+/// A PSI trigger fires at most once per window, so under steady pressure it may fire a bit more
+/// than a window apart, holding the level for two windows keeps it from flipping back and forth.
+fn evaluate(last_fired: &[Option<Instant>; 3], now: Instant, window: Duration) -> PressureLevel {
+    let hold = window * 2;
+    [PressureLevel::Critical, PressureLevel::Warning]
+        .iter()
+        .copied()
+        .find(|&l| last_fired[l as usize].is_some_and(|t| now.duration_since(t) < hold))
+        .unwrap_or(PressureLevel::Normal)
+}
+
+struct Sources {
+    triggers: Vec<(PressureLevel, std::fs::File)>,
+    events: Option<super::cgroup::MemoryEventStat>,
+}
+
+impl Sources {
+    fn open(options: &PressureOptions) -> io::Result<Self> {
+        use super::cgroup::{CgroupVersion, MemoryCgroup, MemoryEventStat};
+        use std::path::PathBuf;
+
+        let cgroup = MemoryCgroup::current().ok();
+        let psi = cgroup
+            .as_ref()
+            .filter(|cgroup| cgroup.version() == CgroupVersion::V2)
+            .map(|cgroup| cgroup.path().join("memory.pressure"))
+            .filter(|path| path.exists())
+            .unwrap_or_else(|| PathBuf::from("/proc/pressure/memory"));
+
+        let mut triggers = vec![];
+        let mut error = None;
+        for (level, kind, stall) in [
+            (PressureLevel::Warning, "some", options.warning_stall),
+            (PressureLevel::Critical, "full", options.critical_stall),
+        ] {
+            match open_trigger(&psi, kind, stall, options.window) {
+                Ok(file) => triggers.push((level, file)),
+                Err(e) => error = Some(e),
+            }
+        }
+        let events = cgroup.and_then(|cgroup| MemoryEventStat::build(cgroup).ok());
+        if triggers.is_empty() && events.is_none() {
+            return Err(error.unwrap_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "no source of memory pressure is available",
+                )
+            }));
+        }
+        Ok(Sources { triggers, events })
+    }
+
+    /// Wait for triggers up to `timeout` and return the levels fired.
+    fn poll(&mut self, timeout: Duration) -> Vec<PressureLevel> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut fired = vec![];
+        if self.triggers.is_empty() {
+            std::thread::sleep(timeout);
+        } else {
+            let mut fds = self
+                .triggers
+                .iter()
+                .map(|(_, file)| libc::pollfd {
+                    fd: file.as_raw_fd(),
+                    events: libc::POLLPRI,
+                    revents: 0,
+                })
+                .collect::<Vec<_>>();
+            let ret = unsafe {
+                libc::poll(
+                    fds.as_mut_ptr(),
+                    fds.len() as libc::nfds_t,
+                    timeout.as_millis() as libc::c_int,
+                )
+            };
+            if ret > 0 {
+                for (fd, (level, _)) in fds.iter().zip(&self.triggers) {
+                    if fd.revents & libc::POLLPRI != 0 {
+                        fired.push(*level);
+                    }
+                }
+                // the trigger is destroyed, e.g. the cgroup is removed.
+                let mut revents = fds.iter().map(|fd| fd.revents);
+                self.triggers.retain(|_| {
+                    revents.next().unwrap_or(0) & (libc::POLLERR | libc::POLLNVAL) == 0
+                });
+            } else if ret < 0 && io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                self.triggers.clear();
+            }
+        }
+
+        if let Some(stat) = self.events.as_mut() {
+            match stat.events() {
+                Ok(events) if events.oom > 0 || events.oom_kill > 0 => {
+                    fired.push(PressureLevel::Critical)
+                }
+                Ok(events) if events.high > 0 || events.max > 0 => {
+                    fired.push(PressureLevel::Warning)
+                }
+                Ok(_) => {}
+                Err(_) => self.events = None,
+            }
+        }
+        fired
+    }
+}
+
+/// Register a PSI trigger, see <https://docs.kernel.org/accounting/psi.html#monitoring-for-pressure-thresholds>.
+fn open_trigger(
+    path: &std::path::Path,
+    kind: &str,
+    stall: Duration,
+    window: Duration,
+) -> io::Result<std::fs::File> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    let trigger = format!("{} {} {}\0", kind, stall.as_micros(), window.as_micros());
+    file.write_all(trigger.as_bytes())?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let window = Duration::from_secs(2);
+        let now = Instant::now();
+        let mut last_fired = [None; 3];
+        assert_eq!(evaluate(&last_fired, now, window), PressureLevel::Normal);
+        last_fired[PressureLevel::Warning as usize] = Some(now);
+        assert_eq!(evaluate(&last_fired, now, window), PressureLevel::Warning);
+        last_fired[PressureLevel::Critical as usize] = Some(now);
+        assert_eq!(evaluate(&last_fired, now, window), PressureLevel::Critical);
+        // fired again a bit more than a window later.
+        last_fired[PressureLevel::Critical as usize] = None;
+        let later = now + window + Duration::from_millis(100);
+        assert_eq!(evaluate(&last_fired, later, window), PressureLevel::Warning);
+        let later = now + window * 2;
+        assert_eq!(evaluate(&last_fired, later, window), PressureLevel::Normal);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let options = PressureOptions {
+            poll_interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let subscription = match subscribe(options, move |level| sender.send(level).unwrap()) {
+            Ok(subscription) => subscription,
+            // there may be neither PSI nor cgroup, e.g. in some sandboxes.
+            Err(e) => {
+                assert!(
+                    matches!(
+                        e.kind(),
+                        io::ErrorKind::Unsupported
+                            | io::ErrorKind::NotFound
+                            | io::ErrorKind::PermissionDenied
+                    ),
+                    "{}",
+                    e
+                );
+                return;
+            }
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        let level = subscription.level();
+        drop(subscription);
+        // the callback is invoked only when the level changes, and every level but the initial one is reported.
+        let changes = receiver.try_iter().collect::<Vec<_>>();
+        let mut previous = PressureLevel::Normal;
+        for &change in &changes {
+            assert_ne!(change, previous);
+            previous = change;
+        }
+        assert!(level == PressureLevel::Normal || changes.contains(&level));
+    }
+}
@@ -1,6 +1,8 @@
-use std::io::{Error, Result};
+use std::io::{Error, ErrorKind, Result};
 
-/// Process Memory Info returned by `get_process_memory_info`
+/// Process Memory Info returned by `get_process_memory_info`.
+///
+/// The fields are in bytes, except on Linux and Android, where they are in pages as read from `statm`.
 #[derive(Clone, Default)]
 pub struct ProcessMemoryInfo {
     /// this is the non-swapped physical memory a process has used.
@@ -45,24 +47,49 @@ pub struct ProcessMemoryInfo {
 }
 
 #[cfg(target_os = "windows")]
-fn get_process_memory_info_impl() -> Result<ProcessMemoryInfo> {
+fn get_process_memory_info_impl(pid: Option<u32>) -> Result<ProcessMemoryInfo> {
     use std::mem::MaybeUninit;
+    use windows_sys::Win32::Foundation::{CloseHandle, ERROR_INVALID_PARAMETER};
     use windows_sys::Win32::System::ProcessStatus::GetProcessMemoryInfo;
     use windows_sys::Win32::System::ProcessStatus::PROCESS_MEMORY_COUNTERS;
-    use windows_sys::Win32::System::Threading::GetCurrentProcess;
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+    let process = match pid {
+        None => unsafe { GetCurrentProcess() },
+        Some(pid) => {
+            let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+            if process == 0 {
+                let err = Error::last_os_error();
+                // OpenProcess fails with ERROR_INVALID_PARAMETER if the process doesn't exist.
+                if err.raw_os_error() == Some(ERROR_INVALID_PARAMETER as i32) {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("process {} not found", pid),
+                    ));
+                }
+                return Err(err);
+            }
+            process
+        }
+    };
     let mut process_memory_counters = MaybeUninit::<PROCESS_MEMORY_COUNTERS>::uninit();
     let ret = unsafe {
         // If the function succeeds, the return value is nonzero.
         // If the function fails, the return value is zero.
         // https://docs.microsoft.com/en-us/windows/win32/api/psapi/nf-psapi-getprocessmemoryinfo
         GetProcessMemoryInfo(
-            GetCurrentProcess(),
+            process,
             process_memory_counters.as_mut_ptr(),
             std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32,
         )
     };
+    let err = Error::last_os_error();
+    if pid.is_some() {
+        unsafe { CloseHandle(process) };
+    }
     if ret == 0 {
-        return Err(Error::last_os_error());
+        return Err(err);
     }
     let process_memory_counters = unsafe { process_memory_counters.assume_init() };
     Ok(ProcessMemoryInfo {
@@ -73,15 +100,25 @@ fn get_process_memory_info_impl() -> Result<ProcessMemoryInfo> {
 }
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
-fn get_process_memory_info_impl() -> Result<ProcessMemoryInfo> {
+fn get_process_memory_info_impl(pid: Option<u32>) -> Result<ProcessMemoryInfo> {
     // https://www.kernel.org/doc/Documentation/filesystems/proc.txt
-    let statm = std::fs::read_to_string("/proc/self/statm")?;
+    let path = match pid {
+        None => "/proc/self/statm".to_string(),
+        Some(pid) => format!("/proc/{}/statm", pid),
+    };
+    let statm = std::fs::read_to_string(&path).map_err(|e| match (pid, e.raw_os_error()) {
+        // the process is gone, reading a process exiting fails with ESRCH.
+        (Some(pid), Some(libc::ENOENT | libc::ESRCH)) => {
+            Error::new(ErrorKind::NotFound, format!("process {} not found", pid))
+        }
+        _ => e,
+    })?;
     let mut parts = statm.split(' ');
-    let Some(virtual_memory_size) = parts.next().and_then(|s| s.parse().ok()) else {
-        return Err(Error::new(std::io::ErrorKind::Other, "Invalid VmSize in /proc/self/statm"));
+    let Some(virtual_memory_size) = parts.next().and_then(|s| s.parse::<u64>().ok()) else {
+        return Err(Error::other(format!("Invalid VmSize in {}", path)));
     };
-    let Some(resident_set_size) = parts.next().and_then(|s| s.parse().ok()) else {
-        return Err(Error::new(std::io::ErrorKind::Other, "Invalid VmRSS in /proc/self/statm"));
+    let Some(resident_set_size) = parts.next().and_then(|s| s.parse::<u64>().ok()) else {
+        return Err(Error::other(format!("Invalid VmRSS in {}", path)));
     };
     Ok(ProcessMemoryInfo {
         virtual_memory_size,
@@ -90,11 +127,42 @@ fn get_process_memory_info_impl() -> Result<ProcessMemoryInfo> {
 }
 
 #[cfg(any(target_os = "macos", target_os = "ios"))]
-fn get_process_memory_info_impl() -> Result<ProcessMemoryInfo> {
+fn get_process_memory_info_impl(pid: Option<u32>) -> Result<ProcessMemoryInfo> {
+    use mach::{
+        kern_return::KERN_SUCCESS, mach_port::mach_port_deallocate, port::mach_port_name_t,
+        traps::mach_task_self, traps::task_for_pid,
+    };
+
+    let pid = match pid {
+        None => return task_memory_info(unsafe { mach_task_self() }),
+        Some(pid) => pid,
+    };
+    let mut task: mach_port_name_t = 0;
+    let kern_ret = unsafe { task_for_pid(mach_task_self(), pid as libc::c_int, &mut task) };
+    if kern_ret != KERN_SUCCESS {
+        // task_for_pid fails with KERN_FAILURE in both cases, so tell them apart by kill(pid, 0).
+        let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0
+            || Error::last_os_error().raw_os_error() != Some(libc::ESRCH);
+        return Err(if alive {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                format!("DARWIN_KERN_RET_CODE:{}", kern_ret),
+            )
+        } else {
+            Error::new(ErrorKind::NotFound, format!("process {} not found", pid))
+        });
+    }
+    let info = task_memory_info(task);
+    unsafe { mach_port_deallocate(mach_task_self(), task) };
+    info
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn task_memory_info(task: mach::port::mach_port_name_t) -> Result<ProcessMemoryInfo> {
     use crate::bindings::task_vm_info;
     use mach::{
         kern_return::KERN_SUCCESS, message::mach_msg_type_number_t, task::task_info,
-        task_info::TASK_VM_INFO, traps::mach_task_self, vm_types::natural_t,
+        task_info::TASK_VM_INFO, vm_types::natural_t,
     };
     use std::mem::MaybeUninit;
 
@@ -109,7 +177,7 @@ fn get_process_memory_info_impl() -> Result<ProcessMemoryInfo> {
 
     let kern_ret = unsafe {
         task_info(
-            mach_task_self(),
+            task,
             TASK_VM_INFO,
             task_vm_info.as_mut_ptr() as *mut _,
             &mut task_info_cnt,
@@ -117,10 +185,7 @@ fn get_process_memory_info_impl() -> Result<ProcessMemoryInfo> {
     };
     if kern_ret != KERN_SUCCESS {
         // see https://docs.rs/mach/0.2.3/mach/kern_return/index.html for more details
-        return Err(Error::new(
-            std::io::ErrorKind::Other,
-            format!("DARWIN_KERN_RET_CODE:{}", kern_ret),
-        ));
+        return Err(Error::other(format!("DARWIN_KERN_RET_CODE:{}", kern_ret)));
     }
     let task_vm_info = unsafe { task_vm_info.assume_init() };
     Ok(ProcessMemoryInfo {
@@ -133,5 +198,27 @@ fn get_process_memory_info_impl() -> Result<ProcessMemoryInfo> {
 }
 
 pub fn get_process_memory_info() -> Result<ProcessMemoryInfo> {
-    get_process_memory_info_impl()
+    get_process_memory_info_impl(None)
+}
+
+/// Get the memory info of process `pid`.
+///
+/// Return an error of `ErrorKind::NotFound` if the process is gone, or `ErrorKind::PermissionDenied`
+/// if access is denied, e.g. `task_for_pid` requires privileges on MacOS.
+pub fn get_process_memory_info_pid(pid: u32) -> Result<ProcessMemoryInfo> {
+    get_process_memory_info_impl(Some(pid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_process_memory_info_pid() {
+        let info = get_process_memory_info_pid(std::process::id()).unwrap();
+        assert!(info.resident_set_size > 0);
+        assert!(info.virtual_memory_size >= info.resident_set_size);
+        let err = get_process_memory_info_pid(u32::MAX / 2).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
 }
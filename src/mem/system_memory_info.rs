@@ -0,0 +1,124 @@
+use std::io::{Error, ErrorKind, Result};
+
+/// System-wide memory info returned by `get_system_memory_info`, all fields are in bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SystemMemoryInfo {
+    /// total usable physical memory.
+    pub total: u64,
+    /// physical memory which is not used at all.
+    pub free: u64,
+    /// an estimate of memory available for starting new applications without swapping,
+    /// including reclaimable page cache and slab.
+    pub available: u64,
+    /// memory used by block device buffers.
+    pub buffers: u64,
+    /// memory used by the page cache, excluding swap cache.
+    pub cached: u64,
+    /// memory waiting to be written back to the disk.
+    pub dirty: u64,
+    /// memory actively being written back to the disk.
+    pub writeback: u64,
+    pub swap_total: u64,
+    pub swap_free: u64,
+    /// memory used by shared memory and tmpfs.
+    pub shmem: u64,
+    /// memory used by the kernel slab allocator.
+    pub slab: u64,
+}
+
+fn parse_meminfo(meminfo: &str) -> Result<SystemMemoryInfo> {
+    let mut info = SystemMemoryInfo::default();
+    let mut has_total = false;
+    for line in meminfo.lines() {
+        let mut parts = line.split_whitespace();
+        let field = match parts.next() {
+            Some("MemTotal:") => {
+                has_total = true;
+                &mut info.total
+            }
+            Some("MemFree:") => &mut info.free,
+            Some("MemAvailable:") => &mut info.available,
+            Some("Buffers:") => &mut info.buffers,
+            Some("Cached:") => &mut info.cached,
+            Some("Dirty:") => &mut info.dirty,
+            Some("Writeback:") => &mut info.writeback,
+            Some("SwapTotal:") => &mut info.swap_total,
+            Some("SwapFree:") => &mut info.swap_free,
+            Some("Shmem:") => &mut info.shmem,
+            Some("Slab:") => &mut info.slab,
+            _ => continue,
+        };
+        let value: u64 = parts.next().and_then(|s| s.parse().ok()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid line in /proc/meminfo: {}", line),
+            )
+        })?;
+        // values are in kB, except the counters of huge pages which are not read.
+        *field = match parts.next() {
+            Some("kB") => value * 1024,
+            _ => value,
+        };
+    }
+    if !has_total {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "MemTotal not found in /proc/meminfo",
+        ));
+    }
+    Ok(info)
+}
+
+/// Get the memory info of the whole system by parsing `/proc/meminfo`.
+pub fn get_system_memory_info() -> Result<SystemMemoryInfo> {
+    // https://www.kernel.org/doc/Documentation/filesystems/proc.txt
+    parse_meminfo(&std::fs::read_to_string("/proc/meminfo")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:       16303388 kB
+MemFree:         1040628 kB
+MemAvailable:   10224536 kB
+Buffers:          531196 kB
+Cached:          8576096 kB
+SwapCached:         1004 kB
+Dirty:               364 kB
+Writeback:             0 kB
+SwapTotal:       2097148 kB
+SwapFree:        2090492 kB
+Shmem:            612852 kB
+Slab:             870912 kB
+HugePages_Total:       0
+";
+        assert_eq!(
+            parse_meminfo(meminfo).unwrap(),
+            SystemMemoryInfo {
+                total: 16303388 * 1024,
+                free: 1040628 * 1024,
+                available: 10224536 * 1024,
+                buffers: 531196 * 1024,
+                cached: 8576096 * 1024,
+                dirty: 364 * 1024,
+                writeback: 0,
+                swap_total: 2097148 * 1024,
+                swap_free: 2090492 * 1024,
+                shmem: 612852 * 1024,
+                slab: 870912 * 1024,
+            }
+        );
+        assert!(parse_meminfo("MemFree: 1 kB\n").is_err());
+    }
+
+    #[test]
+    fn test_get_system_memory_info() {
+        let info = get_system_memory_info().unwrap();
+        assert!(info.total > 0);
+        assert!(info.free <= info.total);
+        assert!(info.available <= info.total);
+    }
+}
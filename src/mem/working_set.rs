@@ -0,0 +1,344 @@
+//! Working set estimation of current process.
+//!
+//! The resident set size tells what is in memory, not what is actually used.
+//! The working set is estimated by clearing the page table bits of current process through
+//! `/proc/self/clear_refs` and counting pages whose bits are set again after an interval:
+//! the referenced bits reported by `/proc/self/smaps_rollup` count pages touched,
+//! and the soft-dirty bits of `/proc/self/pagemap` count pages written.
+//!
+//! Clearing referenced bits also affects how the kernel ages pages for reclaim,
+//! and clearing soft-dirty bits makes the next write to every page fault,
+//! so don't estimate too often, and don't use `Dirty` with other users of soft-dirty bits like CRIU.
+//!
+//! See <https://docs.kernel.org/admin-guide/mm/soft-dirty.html> and
+//! <https://docs.kernel.org/admin-guide/mm/pagemap.html>.
+
+use std::{
+    convert::TryInto,
+    fs::{self, File},
+    io::{Error, ErrorKind, Result},
+    mem,
+    os::unix::fs::FileExt,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Which bits of pages are counted by `WorkingSetStat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkingSetKind {
+    /// pages read or written, by the referenced bits.
+    Referenced,
+    /// pages written, by the soft-dirty bits, which require the kernel is built with `CONFIG_MEM_SOFT_DIRTY`.
+    Dirty,
+}
+
+impl WorkingSetKind {
+    /// The command written to `/proc/self/clear_refs`.
+    fn clear_command(self) -> &'static [u8] {
+        match self {
+            WorkingSetKind::Referenced => b"1",
+            WorkingSetKind::Dirty => b"4",
+        }
+    }
+}
+
+/// The working set returned by `WorkingSetStat::working_set` and `estimate_working_set`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkingSet {
+    /// bytes of pages touched or written in `duration`, depending on `WorkingSetKind`.
+    pub bytes: u64,
+    /// the resident set size at the end of `duration`.
+    pub resident: u64,
+    pub duration: Duration,
+}
+
+impl WorkingSet {
+    /// The ratio of the working set to the resident set size.
+    pub fn ratio(&self) -> f64 {
+        if self.resident == 0 {
+            return 0.0;
+        }
+        self.bytes as f64 / self.resident as f64
+    }
+}
+
+/// A monitor of the working set of current process, which works like `cpu::ProcessStat`.
+pub struct WorkingSetStat {
+    kind: WorkingSetKind,
+    now: Instant,
+}
+
+impl WorkingSetStat {
+    /// Clear the bits of all pages of current process and return a monitor.
+    pub fn cur(kind: WorkingSetKind) -> Result<Self> {
+        clear_refs(kind)?;
+        Ok(WorkingSetStat {
+            kind,
+            now: Instant::now(),
+        })
+    }
+
+    /// Return the working set since last invoke, or when this struct created if it is the first invoke,
+    /// then clear the bits again.
+    pub fn working_set(&mut self) -> Result<WorkingSet> {
+        let (bytes, resident) = match self.kind {
+            WorkingSetKind::Referenced => {
+                let rollup = read_rollup()?;
+                (rollup.referenced, rollup.rss)
+            }
+            WorkingSetKind::Dirty => match soft_dirty_bytes()? {
+                // at least the stack of current thread has been written, so the soft-dirty bits
+                // are always 0 if the kernel is built without `CONFIG_MEM_SOFT_DIRTY`.
+                0 => {
+                    return Err(Error::new(
+                        ErrorKind::Unsupported,
+                        "soft-dirty bits are not supported by the kernel",
+                    ))
+                }
+                bytes => (bytes, read_rollup()?.rss),
+            },
+        };
+        clear_refs(self.kind)?;
+        let old_now = mem::replace(&mut self.now, Instant::now());
+        Ok(WorkingSet {
+            bytes,
+            resident,
+            duration: self.now - old_now,
+        })
+    }
+}
+
+/// Clear the bits, wait for `interval` and return the working set in it.
+pub fn estimate_working_set(kind: WorkingSetKind, interval: Duration) -> Result<WorkingSet> {
+    let mut stat = WorkingSetStat::cur(kind)?;
+    thread::sleep(interval);
+    stat.working_set()
+}
+
+fn clear_refs(kind: WorkingSetKind) -> Result<()> {
+    fs::write("/proc/self/clear_refs", kind.clear_command())
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Rollup {
+    rss: u64,
+    referenced: u64,
+}
+
+/// Sum `Rss` and `Referenced` of the content of `/proc/self/smaps_rollup` or `/proc/self/smaps`.
+fn parse_smaps(smaps: &str) -> Result<Rollup> {
+    let mut rollup = Rollup::default();
+    for line in smaps.lines() {
+        let mut parts = line.split_whitespace();
+        let field = match parts.next() {
+            Some("Rss:") => &mut rollup.rss,
+            Some("Referenced:") => &mut rollup.referenced,
+            _ => continue,
+        };
+        let kb: u64 = parts.next().and_then(|s| s.parse().ok()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid line in smaps: {}", line),
+            )
+        })?;
+        *field += kb * 1024;
+    }
+    Ok(rollup)
+}
+
+fn read_rollup() -> Result<Rollup> {
+    // `smaps_rollup` is available since Linux 4.14, summing `smaps` is much slower.
+    let smaps = match fs::read_to_string("/proc/self/smaps_rollup") {
+        Err(e) if e.kind() == ErrorKind::NotFound => fs::read_to_string("/proc/self/smaps")?,
+        smaps => smaps?,
+    };
+    parse_smaps(&smaps)
+}
+
+const PM_SOFT_DIRTY: u64 = 1 << 55;
+const PM_SWAP: u64 = 1 << 62;
+const PM_PRESENT: u64 = 1 << 63;
+
+/// Count pages which are present or swapped with the soft-dirty bit set.
+fn count_soft_dirty(entries: &[u64]) -> u64 {
+    entries
+        .iter()
+        .filter(|&&entry| entry & PM_SOFT_DIRTY != 0 && entry & (PM_PRESENT | PM_SWAP) != 0)
+        .count() as u64
+}
+
+/// Parse the address ranges of mappings which may have soft-dirty pages out of the content of `/proc/self/smaps`.
+///
+/// Mappings without any access permission, e.g. guard pages and reserved address space,
+/// and mappings with neither resident nor swapped pages are skipped, so that the cost of scanning
+/// doesn't grow with the virtual memory size.
+fn parse_mappings(smaps: &str) -> Result<Vec<(u64, u64)>> {
+    let invalid = |line: &str| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid line in smaps: {}", line),
+        )
+    };
+    let mut mappings = vec![];
+    // the range of current mapping, and whether it has resident or swapped pages.
+    let mut current: Option<((u64, u64), bool)> = None;
+    for line in smaps.lines() {
+        let mut parts = line.split_whitespace();
+        let first = match parts.next() {
+            Some(first) => first,
+            None => continue,
+        };
+        match first {
+            "Rss:" | "Swap:" => {
+                let kb: u64 = parts
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| invalid(line))?;
+                if let Some((_, used)) = current.as_mut() {
+                    *used |= kb > 0;
+                }
+            }
+            // other fields of current mapping.
+            field if field.ends_with(':') => {}
+            // the header of a mapping like `7f0000000000-7f0000001000 rw-p 00000000 00:00 0 [heap]`.
+            range => {
+                mappings.extend(current.take().filter(|m| m.1).map(|m| m.0));
+                let (start, end) = range
+                    .split_once('-')
+                    .and_then(|(start, end)| {
+                        Some((
+                            u64::from_str_radix(start, 16).ok()?,
+                            u64::from_str_radix(end, 16).ok()?,
+                        ))
+                    })
+                    .ok_or_else(|| invalid(line))?;
+                let perms = parts.next().ok_or_else(|| invalid(line))?;
+                // the vsyscall page is out of the address space of user mode, reading it fails.
+                if !perms.starts_with("---") && !line.ends_with("[vsyscall]") {
+                    current = Some(((start, end), false));
+                }
+            }
+        }
+    }
+    mappings.extend(current.filter(|m| m.1).map(|m| m.0));
+    Ok(mappings)
+}
+
+/// Scan `/proc/self/pagemap` for all mappings and return the bytes of soft-dirty pages.
+fn soft_dirty_bytes() -> Result<u64> {
+    const ENTRIES_PER_READ: usize = 4096;
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let pagemap = File::open("/proc/self/pagemap")?;
+    let mappings = parse_mappings(&fs::read_to_string("/proc/self/smaps")?)?;
+    let mut buf = vec![0u8; ENTRIES_PER_READ * 8];
+    let mut entries = Vec::with_capacity(ENTRIES_PER_READ);
+    let mut pages = 0;
+    for (start, end) in mappings {
+        let mut page = start / page_size;
+        while page < end / page_size {
+            let count = ((end / page_size - page) as usize).min(ENTRIES_PER_READ);
+            let read = pagemap.read_at(&mut buf[..count * 8], page * 8)?;
+            if read == 0 {
+                break;
+            }
+            entries.clear();
+            entries.extend(
+                buf[..read - read % 8]
+                    .chunks_exact(8)
+                    .map(|entry| u64::from_ne_bytes(entry.try_into().unwrap())),
+            );
+            pages += count_soft_dirty(&entries);
+            page += (read / 8) as u64;
+        }
+    }
+    Ok(pages * page_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_smaps() {
+        let smaps =
+            "555e002c4000-7ffc9df82000 ---p 00000000 00:00 0                          [rollup]
+Rss:                1384 kB
+Pss:                 404 kB
+Private_Dirty:       100 kB
+Referenced:         1000 kB
+Anonymous:           100 kB
+";
+        assert_eq!(
+            parse_smaps(smaps).unwrap(),
+            Rollup {
+                rss: 1384 * 1024,
+                referenced: 1000 * 1024,
+            }
+        );
+        assert!(parse_smaps("Rss: x kB").is_err());
+    }
+
+    #[test]
+    fn test_parse_mappings() {
+        let smaps =
+            "555e002c4000-555e002c6000 r--p 00000000 08:01 1234                       /usr/bin/cat
+Rss:                   8 kB
+Swap:                  0 kB
+VmFlags: rd mr mw me sd
+7f0000000000-7f0000100000 ---p 00000000 00:00 0
+Rss:                   0 kB
+Swap:                  0 kB
+7f0000100000-7f0000200000 rw-p 00000000 00:00 0
+Rss:                   0 kB
+Swap:                  0 kB
+7f0000200000-7f0000300000 rw-p 00000000 00:00 0
+Rss:                   0 kB
+Swap:                 12 kB
+ffffffffff600000-ffffffffff601000 --xp 00000000 00:00 0                  [vsyscall]
+Rss:                   4 kB
+";
+        assert_eq!(
+            parse_mappings(smaps).unwrap(),
+            vec![
+                (0x555e002c4000, 0x555e002c6000),
+                (0x7f0000200000, 0x7f0000300000),
+            ]
+        );
+        assert!(parse_mappings("x-y rw-p").is_err());
+    }
+
+    #[test]
+    fn test_count_soft_dirty() {
+        let entries = [
+            PM_PRESENT | PM_SOFT_DIRTY | 42,
+            PM_PRESENT | 43,
+            PM_SWAP | PM_SOFT_DIRTY,
+            // not present, the soft-dirty bit is meaningless.
+            PM_SOFT_DIRTY,
+        ];
+        assert_eq!(count_soft_dirty(&entries), 2);
+    }
+
+    #[test]
+    fn test_working_set() {
+        const SIZE: usize = 16 * 1024 * 1024;
+        let mut buf = vec![1u8; SIZE];
+        for kind in [WorkingSetKind::Referenced, WorkingSetKind::Dirty] {
+            let mut stat = WorkingSetStat::cur(kind).unwrap();
+            for i in (0..buf.len()).step_by(4096) {
+                buf[i] = buf[i].wrapping_add(1);
+            }
+            std::hint::black_box(&buf);
+            let working_set = match stat.working_set() {
+                Err(e) if e.kind() == ErrorKind::Unsupported => continue,
+                working_set => working_set.unwrap(),
+            };
+            // the kernel may not count some pages, e.g. parts of transparent huge pages.
+            assert!(working_set.bytes >= SIZE as u64 / 2, "{:?}", working_set);
+            if kind == WorkingSetKind::Referenced {
+                assert!(working_set.resident >= working_set.bytes);
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+#[cfg(feature = "heap_profiler")]
+pub mod pprof;
 pub mod ptr_upgrade;
 #[cfg(windows)]
 pub mod windows_handle;
@@ -0,0 +1,236 @@
+//! A minimal encoder of the pprof [profile.proto] format, uncompressed.
+//!
+//! [profile.proto]: https://github.com/google/pprof/blob/main/proto/profile.proto
+
+use std::collections::HashMap;
+
+/// A source line of a location, the first line is the innermost inlined function.
+pub struct Line {
+    pub function: String,
+    pub filename: String,
+    pub line: i64,
+}
+
+struct Location {
+    id: u64,
+    address: u64,
+    lines: Vec<(u64, i64)>,
+}
+
+struct Sample {
+    location_ids: Vec<u64>,
+    values: Vec<i64>,
+}
+
+pub struct ProfileBuilder {
+    strings: Vec<String>,
+    string_ids: HashMap<String, i64>,
+    // (id, name, filename)
+    functions: Vec<(u64, i64, i64)>,
+    function_ids: HashMap<(i64, i64), u64>,
+    locations: Vec<Location>,
+    location_ids: HashMap<u64, u64>,
+    samples: Vec<Sample>,
+    sample_types: Vec<(i64, i64)>,
+    period_type: (i64, i64),
+    period: i64,
+    drop_frames: i64,
+    time_nanos: i64,
+}
+
+impl ProfileBuilder {
+    /// `sample_types` and `period_type` are pairs of `(type, unit)`.
+    pub fn new(sample_types: &[(&str, &str)], period_type: (&str, &str), period: i64) -> Self {
+        let mut builder = ProfileBuilder {
+            strings: vec![],
+            string_ids: HashMap::new(),
+            functions: vec![],
+            function_ids: HashMap::new(),
+            locations: vec![],
+            location_ids: HashMap::new(),
+            samples: vec![],
+            sample_types: vec![],
+            period_type: (0, 0),
+            period,
+            drop_frames: 0,
+            time_nanos: 0,
+        };
+        // string_table[0] must be "".
+        builder.string("");
+        builder.sample_types = sample_types
+            .iter()
+            .map(|(ty, unit)| (builder.string(ty), builder.string(unit)))
+            .collect();
+        builder.period_type = (builder.string(period_type.0), builder.string(period_type.1));
+        builder
+    }
+
+    fn string(&mut self, s: &str) -> i64 {
+        if let Some(&id) = self.string_ids.get(s) {
+            return id;
+        }
+        let id = self.strings.len() as i64;
+        self.strings.push(s.to_owned());
+        self.string_ids.insert(s.to_owned(), id);
+        id
+    }
+
+    /// Frames whose function name fully matches `regex` are dropped with their callees.
+    pub fn drop_frames(&mut self, regex: &str) {
+        self.drop_frames = self.string(regex);
+    }
+
+    pub fn time_nanos(&mut self, time_nanos: i64) {
+        self.time_nanos = time_nanos;
+    }
+
+    /// Return the location id of `address`, `resolve` is invoked only for new addresses.
+    pub fn location(&mut self, address: u64, resolve: impl FnOnce() -> Vec<Line>) -> u64 {
+        if let Some(&id) = self.location_ids.get(&address) {
+            return id;
+        }
+        let lines = resolve()
+            .into_iter()
+            .map(|line| {
+                let key = (self.string(&line.function), self.string(&line.filename));
+                let next_id = self.functions.len() as u64 + 1;
+                let function_id = *self.function_ids.entry(key).or_insert(next_id);
+                if function_id == next_id {
+                    self.functions.push((function_id, key.0, key.1));
+                }
+                (function_id, line.line)
+            })
+            .collect();
+        let id = self.locations.len() as u64 + 1;
+        self.locations.push(Location { id, address, lines });
+        self.location_ids.insert(address, id);
+        id
+    }
+
+    /// `location_ids` starts from the leaf, `values` matches `sample_types` one by one.
+    pub fn sample(&mut self, location_ids: Vec<u64>, values: Vec<i64>) {
+        self.samples.push(Sample {
+            location_ids,
+            values,
+        });
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for &(ty, unit) in &self.sample_types {
+            put_message(&mut buf, 1, |m| value_type(m, ty, unit));
+        }
+        for sample in &self.samples {
+            put_message(&mut buf, 2, |m| {
+                put_packed(m, 1, sample.location_ids.iter().copied());
+                put_packed(m, 2, sample.values.iter().map(|&v| v as u64));
+            });
+        }
+        for location in &self.locations {
+            put_message(&mut buf, 4, |m| {
+                put_varint_field(m, 1, location.id);
+                put_varint_field(m, 3, location.address);
+                for &(function_id, line) in &location.lines {
+                    put_message(m, 4, |l| {
+                        put_varint_field(l, 1, function_id);
+                        put_varint_field(l, 2, line as u64);
+                    });
+                }
+            });
+        }
+        for &(id, name, filename) in &self.functions {
+            put_message(&mut buf, 5, |m| {
+                put_varint_field(m, 1, id);
+                put_varint_field(m, 2, name as u64);
+                put_varint_field(m, 3, name as u64);
+                put_varint_field(m, 4, filename as u64);
+            });
+        }
+        for s in &self.strings {
+            put_bytes(&mut buf, 6, s.as_bytes());
+        }
+        put_varint_field(&mut buf, 7, self.drop_frames as u64);
+        put_varint_field(&mut buf, 9, self.time_nanos as u64);
+        put_message(&mut buf, 11, |m| {
+            value_type(m, self.period_type.0, self.period_type.1)
+        });
+        put_varint_field(&mut buf, 12, self.period as u64);
+        buf
+    }
+}
+
+fn value_type(buf: &mut Vec<u8>, ty: i64, unit: i64) {
+    put_varint_field(buf, 1, ty as u64);
+    put_varint_field(buf, 2, unit as u64);
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u32, v: u64) {
+    // default values are omitted in proto3.
+    if v != 0 {
+        put_varint(buf, (field as u64) << 3);
+        put_varint(buf, v);
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_varint(buf, (field as u64) << 3 | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_message(buf: &mut Vec<u8>, field: u32, f: impl FnOnce(&mut Vec<u8>)) {
+    let mut message = vec![];
+    f(&mut message);
+    put_bytes(buf, field, &message);
+}
+
+fn put_packed(buf: &mut Vec<u8>, field: u32, values: impl Iterator<Item = u64>) {
+    let mut packed = vec![];
+    values.for_each(|v| put_varint(&mut packed, v));
+    put_bytes(buf, field, &packed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        let mut buf = vec![];
+        put_varint(&mut buf, 1);
+        put_varint(&mut buf, 300);
+        put_varint(&mut buf, u64::MAX);
+        assert_eq!(
+            buf,
+            [1, 0xac, 0x02, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut builder = ProfileBuilder::new(&[("space", "bytes")], ("space", "bytes"), 1);
+        let line = || {
+            vec![Line {
+                function: "main".to_owned(),
+                filename: "main.rs".to_owned(),
+                line: 1,
+            }]
+        };
+        let id = builder.location(0x10, line);
+        assert_eq!(builder.location(0x10, || unreachable!()), id);
+        builder.sample(vec![id], vec![42]);
+        let buf = builder.encode();
+        // sample_type { type: 1, unit: 2 }
+        assert_eq!(buf[..6], [0x0a, 0x04, 0x08, 0x01, 0x10, 0x02]);
+        // sample { location_id: [1], value: [42] }
+        assert_eq!(buf[6..14], [0x12, 0x06, 0x0a, 0x01, 0x01, 0x12, 0x01, 42]);
+    }
+}